@@ -36,7 +36,8 @@ if [ -z "$DEV" ]; then
   nginx -c /tmp/nginx.conf
 fi
 
-# Import the GPG key that's specified in the secrets file
+# Import the GPG key that's specified in the secrets file, still needed by
+# rustbuild when promote-release is configured with `build-manifest = "x.py"`
 gpg --batch --import `tq dist.gpg-key < $secrets`
 
 # Configure and run homu
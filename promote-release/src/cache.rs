@@ -0,0 +1,300 @@
+//! A cache of the artifacts downloaded from CI, kept in the work directory so
+//! that retrying the release of a rev doesn't download everything again.
+//!
+//! Artifacts are stored at `$rev/$etag/$key`, so an artifact re-uploaded by CI
+//! is never mistaken for the one cached before, and are linked into the
+//! download directory from there. Whole revs are evicted, least recently used
+//! first, once the cache grows past its size limit or the disk it's on runs
+//! low on space.
+//!
+//! Several channels may be released at once and share the cache, possibly
+//! even the same rev, so a release claims its rev for as long as it uses the
+//! artifacts and revs claimed by anyone are never removed.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use fs2::{self, FileExt};
+
+use errors::{Error, Result};
+use {file_name, files_in};
+use storage::Object;
+
+/// Touched whenever a rev's artifacts are used, to tell which revs were used
+/// least recently, and locked by everyone using them.
+const USED: &str = ".used";
+
+pub struct Cache {
+    dir: PathBuf,
+    max_bytes: u64,
+    min_free: u64,
+}
+
+/// A shared lock on a rev's artifacts, which keeps them in the cache until
+/// it's dropped.
+pub struct Claim {
+    _lock: File,
+}
+
+impl Cache {
+    /// A cache in `dir` of at most `max_bytes`, which evicts revs while
+    /// there's less than `min_free` bytes of free space.
+    pub fn new(dir: PathBuf, max_bytes: u64, min_free: u64) -> Cache {
+        Cache { dir, max_bytes, min_free }
+    }
+
+    /// Where `object`, listed underneath the CI prefix of `rev`, is cached.
+    pub fn path(&self, rev: &str, object: &Object) -> PathBuf {
+        let etag = object.etag.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+        self.dir.join(rev).join(etag).join(&object.key)
+    }
+
+    /// Whether all of `object` is in the cache.
+    pub fn contains(&self, rev: &str, object: &Object) -> bool {
+        fs::metadata(self.path(rev, object)).map(|m| m.len() == object.size).unwrap_or(false)
+    }
+
+    /// Marks `rev` as used and keeps it from being removed by anyone else
+    /// until the returned claim is dropped.
+    pub fn claim(&self, rev: &str) -> Result<Claim> {
+        let dir = self.dir.join(rev);
+        loop {
+            t!(fs::create_dir_all(&dir));
+            let file = t!(File::create(dir.join(USED)));
+            t!(file.lock_shared());
+            // Whoever we waited for may have been removing the rev, in which
+            // case it has to be created again.
+            if dir.join(USED).exists() {
+                return Ok(Claim { _lock: file })
+            }
+        }
+    }
+
+    /// Removes whatever is cached for the claimed `rev` which isn't among
+    /// `objects` any more.
+    pub fn prune(&self, rev: &str, objects: &[Object]) -> Result<()> {
+        let dir = self.dir.join(rev);
+        let wanted = objects.iter().map(|o| self.path(rev, o)).collect::<Vec<_>>();
+        for file in files_in(&dir)? {
+            if file.parent() != Some(&dir) && !wanted.contains(&file) {
+                t!(fs::remove_file(&file));
+                // Fails unless that was the last file cached for its ETag.
+                drop(fs::remove_dir(file.parent().unwrap()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `object` to the cache by calling `download` with a temporary path
+    /// to write it to, so that failed downloads never look cached.
+    pub fn fill<F>(&self, rev: &str, object: &Object, download: F) -> Result<()>
+        where F: FnOnce(&Path) -> Result<()>
+    {
+        let path = self.path(rev, object);
+        t!(fs::create_dir_all(path.parent().unwrap()));
+        let tmp = path.with_file_name(format!("{}.part", file_name(&path)?));
+        download(&tmp)?;
+        t!(fs::rename(&tmp, &path));
+        Ok(())
+    }
+
+    /// Makes the cached `object` available at `dst`, copying it if it can't
+    /// be hard linked. Whatever ends up at `dst` must be replaced rather than
+    /// written to, as that would change the cached copy as well.
+    pub fn link(&self, rev: &str, object: &Object, dst: &Path) -> Result<()> {
+        let path = self.path(rev, object);
+        t!(fs::create_dir_all(dst.parent().unwrap()));
+        if fs::hard_link(&path, dst).is_err() {
+            t!(fs::copy(&path, dst));
+        }
+        Ok(())
+    }
+
+    /// Removes everything cached for `rev`, returning whether it did, which
+    /// it doesn't while anyone has the rev claimed. Our own claim on it has
+    /// to be dropped first.
+    pub fn remove(&self, rev: &str) -> Result<bool> {
+        let dir = self.dir.join(rev);
+        if !dir.exists() {
+            return Ok(true)
+        }
+        // Nobody can have the rev claimed without its `.used`.
+        let _lock = match File::open(dir.join(USED)) {
+            Ok(file) => match file.try_lock_exclusive() {
+                Ok(()) => Some(file),
+                Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    return Ok(false)
+                }
+                Err(e) => return Err(Error::Io("locking the download cache".to_string(), e)),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::Io("locking the download cache".to_string(), e)),
+        };
+        t!(fs::remove_dir_all(&dir));
+        Ok(true)
+    }
+
+    /// Evicts unclaimed revs, least recently used first, until the cache fits
+    /// in its size limit and `needed` bytes can be written while still
+    /// leaving the minimum free space.
+    pub fn evict(&self, needed: u64) -> Result<()> {
+        if !self.dir.exists() {
+            return Ok(())
+        }
+        let mut revs = Vec::new();
+        let mut total = 0;
+        for entry in t!(self.dir.read_dir()) {
+            let path = t!(entry).path();
+            let size = files_in(&path)?.iter()
+                .map(|f| fs::metadata(f).map(|m| m.len()).unwrap_or(0))
+                .sum::<u64>();
+            total += size;
+            let rev = file_name(&path)?.to_string();
+            let used = fs::metadata(path.join(USED))
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            revs.push((used, rev, size));
+        }
+        revs.sort();
+        for (_, rev, size) in revs {
+            let available = t!(fs2::available_space(&self.dir));
+            if total <= self.max_bytes && available >= needed.saturating_add(self.min_free) {
+                break
+            }
+            if !self.remove(&rev)? {
+                println!("not evicting {} from the download cache: in use", rev);
+                continue
+            }
+            println!("evicted {} ({} bytes) from the download cache", rev, size);
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use errors::Error;
+    use storage::Object;
+    use tests::{write_file, Env, TempDir, DATE};
+    use super::{Cache, Claim};
+    use {Context, Task};
+
+    /// Caches `size` bytes for `rev`, returning its claim on them.
+    fn fill(cache: &Cache, rev: &str, size: usize) -> Claim {
+        let object = Object {
+            key: "rust.tar.gz".to_string(),
+            size: size as u64,
+            etag: "e".to_string(),
+        };
+        let claim = cache.claim(rev).unwrap();
+        cache.fill(rev, &object, |tmp| {
+            fs::write(tmp, vec![0; size]).unwrap();
+            Ok(())
+        }).unwrap();
+        assert!(cache.contains(rev, &object));
+        // Tells the revs apart by when they were used.
+        thread::sleep(Duration::from_millis(10));
+        claim
+    }
+
+    fn revs(dir: &TempDir) -> Vec<String> {
+        let mut revs = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        revs.sort();
+        revs
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = TempDir::new("cache-lru");
+        let cache = Cache::new(dir.path().to_path_buf(), 150, 0);
+        for rev in ["b", "a", "c"].iter() {
+            fill(&cache, rev, 100);
+        }
+        cache.evict(0).unwrap();
+        assert_eq!(revs(&dir), ["c"]);
+    }
+
+    #[test]
+    fn keeps_claimed_revs() {
+        let dir = TempDir::new("cache-claimed");
+        let cache = Cache::new(dir.path().to_path_buf(), 0, 0);
+        let claim = fill(&cache, "a", 100);
+        fill(&cache, "b", 100);
+        cache.evict(0).unwrap();
+        assert_eq!(revs(&dir), ["a"]);
+
+        assert!(!cache.remove("a").unwrap());
+        drop(claim);
+        assert!(cache.remove("a").unwrap());
+        assert!(revs(&dir).is_empty());
+    }
+
+    #[test]
+    fn evicts_to_free_space() {
+        let dir = TempDir::new("cache-space");
+        let cache = Cache::new(dir.path().to_path_buf(), u64::MAX, 0);
+        let claim = fill(&cache, "a", 100);
+        fill(&cache, "b", 100);
+        cache.evict(0).unwrap();
+        assert_eq!(revs(&dir), ["a", "b"]);
+
+        // There's never this much space, so everything unclaimed has to go.
+        cache.evict(u64::MAX).unwrap();
+        assert_eq!(revs(&dir), ["a"]);
+
+        let cache = Cache::new(dir.path().to_path_buf(), u64::MAX, u64::MAX);
+        drop(claim);
+        cache.evict(0).unwrap();
+        assert!(revs(&dir).is_empty());
+    }
+
+    #[test]
+    fn ensures_space_beyond_what_is_kept_free() {
+        let env = Env::new("ensure-space");
+        fs::create_dir_all(env.work()).unwrap();
+        let cx = env.context(Task::Release, "nightly", None);
+        cx.ensure_space("downloading", 0).unwrap();
+        match cx.ensure_space("downloading", u64::MAX) {
+            Err(Error::DiskSpace(ref msg)) if msg.starts_with("downloading needs") => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // No disk is big enough to keep this much free.
+        let secrets = env.secrets().replace("min-free-gb = 0", "min-free-gb = 1000000000");
+        let cx = Context::new(Task::Release,
+                              env.work(),
+                              "nightly".to_string(),
+                              secrets.parse().unwrap(),
+                              None,
+                              DATE.to_string()).unwrap();
+        match cx.ensure_space("downloading", 0) {
+            Err(Error::DiskSpace(ref msg)) if msg.contains("more are kept free") => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn cleans_and_reports_what_was_freed() {
+        let env = Env::new("clean");
+        let mut cx = env.context(Task::Release, "nightly", None);
+        let dl = env.work().join("nightly/dl");
+        write_file(&dl.join("rust.tar.gz"), "abc");
+        write_file(&dl.join("nested/rust.tar.xz"), "abcde");
+        cx.clean(&dl).unwrap();
+        assert!(!dl.exists());
+        assert_eq!(cx.report.cleaned, [(dl.display().to_string(), 8)]);
+
+        // Cleaning what's already gone isn't reported again.
+        cx.clean(&dl).unwrap();
+        assert_eq!(cx.report.cleaned.len(), 1);
+    }
+}
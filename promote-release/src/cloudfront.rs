@@ -0,0 +1,279 @@
+//! A small native client for CloudFront invalidations, replacing
+//! `aws cloudfront create-invalidation`.
+//!
+//! Paths are invalidated in batches which stay within CloudFront's limits on
+//! invalidations in progress at once, and we wait for every batch to complete
+//! so that whatever runs after us sees what was published. The endpoint is
+//! configurable so this can be pointed at a local mock of the API.
+
+use std::collections::BTreeSet;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::{Error, Result};
+use s3::{retry_result, xml_tag, Signer};
+
+const API: &str = "2020-05-31";
+
+/// Most individual paths which may be in progress at once per distribution.
+const MAX_PATHS: usize = 3000;
+
+/// Most wildcard paths which may be in progress at once per distribution.
+const MAX_WILDCARDS: usize = 15;
+
+/// Paths per invalidation request.
+const BATCH: usize = 1000;
+
+pub struct CloudFront {
+    signer: Signer,
+    /// How long to wait for invalidations to complete.
+    timeout: Duration,
+    /// How often to check on invalidations in progress.
+    poll: Duration,
+}
+
+impl CloudFront {
+    /// Creates a client talking to `endpoint`, normally
+    /// `https://cloudfront.amazonaws.com`.
+    pub fn new(access_key: &str, secret_key: &str, endpoint: &str, timeout: Duration)
+        -> CloudFront
+    {
+        CloudFront {
+            // CloudFront is global, but requests are signed for us-east-1.
+            signer: Signer::new(access_key, secret_key, "us-east-1", "cloudfront", endpoint),
+            timeout,
+            poll: Duration::from_secs(10),
+        }
+    }
+
+    /// Invalidates `paths` in `distribution` and waits for that to finish,
+    /// returning the IDs of the invalidations created.
+    pub fn invalidate(&self, distribution: &str, paths: &[String]) -> Result<Vec<String>> {
+        let (wildcards, exact): (Vec<_>, Vec<_>) = paths.iter().partition(|p| p.ends_with('*'));
+        let batches = exact.chunks(BATCH).chain(wildcards.chunks(MAX_WILDCARDS));
+
+        let mut ids = Vec::new();
+        let mut pending = Vec::new();
+        let (mut paths_pending, mut wildcards_pending) = (0, 0);
+        for batch in batches {
+            let batch_wildcards = batch.iter().filter(|p| p.ends_with('*')).count();
+            if paths_pending + batch.len() - batch_wildcards > MAX_PATHS ||
+               wildcards_pending + batch_wildcards > MAX_WILDCARDS {
+                self.wait(distribution, &pending)?;
+                pending.clear();
+                paths_pending = 0;
+                wildcards_pending = 0;
+            }
+            let id = self.create(distribution, batch)?;
+            println!("created invalidation {} of {} paths in {}", id, batch.len(), distribution);
+            paths_pending += batch.len() - batch_wildcards;
+            wildcards_pending += batch_wildcards;
+            pending.push(id.clone());
+            ids.push(id);
+        }
+        self.wait(distribution, &pending)?;
+        Ok(ids)
+    }
+
+    fn create(&self, distribution: &str, paths: &[&String]) -> Result<String> {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <InvalidationBatch xmlns=\"http://cloudfront.amazonaws.com/doc/{}/\">\
+             <Paths><Quantity>{}</Quantity><Items>{}</Items></Paths>\
+             <CallerReference>promote-release-{}</CallerReference>\
+             </InvalidationBatch>",
+            API,
+            paths.len(),
+            paths.iter().map(|p| format!("<Path>{}</Path>", escape(p))).collect::<String>(),
+            rand::random::<u64>());
+        let path = format!("/{}/distribution/{}/invalidation", API, distribution);
+        let response = self.request("POST", &path, body.as_bytes())?;
+        match xml_tag(&response, "Id") {
+            ref id if id.is_empty() => {
+                Err(Error::Invalidation(format!("no invalidation id in response: {}", response)))
+            }
+            id => Ok(id),
+        }
+    }
+
+    /// Waits for all of `ids` to complete, giving up after `self.timeout`.
+    fn wait(&self, distribution: &str, ids: &[String]) -> Result<()> {
+        let start = Instant::now();
+        let mut waiting = ids.iter().cloned().collect::<BTreeSet<_>>();
+        loop {
+            for id in waiting.clone() {
+                let path = format!("/{}/distribution/{}/invalidation/{}", API, distribution, id);
+                if xml_tag(&self.request("GET", &path, &[])?, "Status") == "Completed" {
+                    println!("invalidation {} completed", id);
+                    waiting.remove(&id);
+                }
+            }
+            if waiting.is_empty() {
+                return Ok(())
+            }
+            if start.elapsed() >= self.timeout {
+                return Err(Error::Invalidation(format!(
+                    "timed out after {}s waiting for {}",
+                    self.timeout.as_secs(),
+                    waiting.into_iter().collect::<Vec<_>>().join(", "))))
+            }
+            thread::sleep(self.poll);
+        }
+    }
+
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> Result<String> {
+        let what = format!("{} {}", method, path);
+        retry_result(&what, || {
+            let mut response = Vec::new();
+            let headers = [("Content-Type", "text/xml".to_string())];
+            self.signer.request(method, path, &[], &headers, body, &mut response)?;
+            String::from_utf8(response).map_err(|e| e.to_string())
+        }).map_err(|e| Error::Invalidation(format!("{} failed: {}", what, e)))
+    }
+}
+
+/// Shortens `paths`, which all start with `/`, to at most `max` by replacing
+/// all paths with a common prefix by a wildcard for it. Prefixes end at a `/`
+/// or a `-`, so that for example `/dist/rust-std-nightly-*` can stand in for
+/// the artifacts of one package without invalidating the rest of `/dist`.
+///
+/// The prefixes are kept as long as possible while using no more wildcards
+/// than may be in progress at once, so that invalidating them never has to
+/// wait for an earlier batch of wildcards.
+pub fn collapse(paths: Vec<String>, max: usize) -> Vec<String> {
+    let paths = paths.into_iter().collect::<BTreeSet<_>>();
+    if paths.len() <= max {
+        return paths.into_iter().collect()
+    }
+    let mut best = vec!["/*".to_string()];
+    for depth in 2.. {
+        let collapsed = paths.iter().map(|p| wildcard(p, depth)).collect::<BTreeSet<_>>();
+        if collapsed.len() > max {
+            return best
+        }
+        if collapsed.iter().filter(|p| p.ends_with('*')).count() <= MAX_WILDCARDS {
+            best = collapsed.into_iter().collect();
+        }
+    }
+    unreachable!()
+}
+
+/// `path` if it has at most `depth` prefixes, or else a wildcard for
+/// everything starting with the first `depth` of them.
+fn wildcard(path: &str, depth: usize) -> String {
+    match path.match_indices(['/', '-']).nth(depth - 1) {
+        Some((i, _)) if i + 1 < path.len() => format!("{}*", &path[..=i]),
+        _ => path.to_string(),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use errors::Error;
+    use tests::{cloudfront, Server};
+    use super::{collapse, CloudFront, MAX_WILDCARDS};
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn mock(complete: bool) -> (Server, CloudFront) {
+        let server = cloudfront(complete);
+        let client = CloudFront::new("key", "secret", &server.url, Duration::from_secs(0));
+        (server, client)
+    }
+
+    #[test]
+    fn collapses_nothing_below_max() {
+        let input = paths(&["/dist/b.toml", "/dist/a.toml", "/dist/a.toml"]);
+        assert_eq!(collapse(input, 2), paths(&["/dist/a.toml", "/dist/b.toml"]));
+    }
+
+    #[test]
+    fn collapses_packages_rather_than_directories() {
+        let mut input = Vec::new();
+        for package in ["rust", "rust-std", "cargo", "rustc", "rust-docs"].iter() {
+            for target in ["x86_64-unknown-linux-gnu", "i686-pc-windows-msvc"].iter() {
+                for ext in [".tar.gz", ".tar.xz", ".tar.gz.sha256", ".tar.xz.sha256"].iter() {
+                    input.push(format!("/dist/{}-nightly-{}{}", package, target, ext));
+                }
+            }
+        }
+        input.push("/dist/channel-rust-nightly.toml".to_string());
+        input.push("/dist/channel-rust-nightly.toml.asc".to_string());
+        assert_eq!(collapse(input.clone(), 5), paths(&[
+            "/dist/cargo-*",
+            "/dist/channel-*",
+            "/dist/rust-*",
+            "/dist/rustc-*",
+        ]));
+        assert_eq!(collapse(input, 8), paths(&[
+            "/dist/cargo-nightly-*",
+            "/dist/channel-rust-*",
+            "/dist/rust-docs-*",
+            "/dist/rust-nightly-*",
+            "/dist/rust-std-*",
+            "/dist/rustc-nightly-*",
+        ]));
+    }
+
+    #[test]
+    fn collapses_within_the_wildcard_limit() {
+        // Too many directories to give each a wildcard of its own.
+        let input = (0..20)
+            .flat_map(|i| vec![format!("/doc/{}/a.html", i), format!("/doc/{}/b.html", i)])
+            .collect::<Vec<_>>();
+        assert_eq!(collapse(input.clone(), 30), paths(&["/doc/*"]));
+
+        // Paths which are exact at a depth don't count against the limit.
+        let mut input = input;
+        input.extend((0..10).map(|i| format!("/index-{}.html", i)));
+        let collapsed = collapse(input, 40);
+        assert!(collapsed.iter().filter(|p| p.ends_with('*')).count() <= MAX_WILDCARDS);
+        assert_eq!(collapsed, paths(&["/doc/*", "/index-*"]));
+    }
+
+    #[test]
+    fn batches_within_limits() {
+        let (server, client) = mock(true);
+        let mut input = (0..3500).map(|i| format!("/dist/{}.tar.gz", i)).collect::<Vec<_>>();
+        input.extend((0..20).map(|i| format!("/doc/{}/*", i)));
+        let ids = client.invalidate("DIST", &input).unwrap();
+
+        // Three full batches of exact paths fill the limit of those in
+        // progress, so the fourth waits for them, and the wildcards are only
+        // sent once the earlier ones have completed too.
+        assert_eq!(ids, ["I0-1000", "I1-1000", "I2-1000", "I3-500", "I4-15", "I5-5"]);
+        let create = "POST /2020-05-31/distribution/DIST/invalidation";
+        let get = |id: &str| format!("GET /2020-05-31/distribution/DIST/invalidation/{}", id);
+        assert_eq!(server.requests(), [
+            create.to_string(),
+            create.to_string(),
+            create.to_string(),
+            get("I0-1000"),
+            get("I1-1000"),
+            get("I2-1000"),
+            create.to_string(),
+            create.to_string(),
+            get("I3-500"),
+            get("I4-15"),
+            create.to_string(),
+            get("I5-5"),
+        ]);
+    }
+
+    #[test]
+    fn times_out() {
+        let (_server, client) = mock(false);
+        match client.invalidate("DIST", &paths(&["/dist/channel-rust-nightly.toml"])) {
+            Err(Error::Invalidation(msg)) => assert!(msg.contains("I0-1"), "{}", msg),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Which components a release has to include before we'll publish it.
+//!
+//! Requirements are configured in the `[dist.components]` section of the
+//! secrets as a list of rules, each applying to some channels and targets:
+//!
+//! ```toml
+//! [dist.components.tiers]
+//! tier-1 = ["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc"]
+//!
+//! [[dist.components.rules]]
+//! channels = ["nightly"]      # defaults to every channel
+//! tier = "tier-1"             # or `targets = [...]`
+//! required = ["rustc", "rust-std", "cargo"]
+//! warn = ["rustfmt", "clippy"]
+//! ```
+//!
+//! Without any configuration only nightly is checked, and only for
+//! `x86_64-unknown-linux-gnu`.
+
+use std::collections::BTreeSet;
+
+use toml::Value;
+
+use errors::{Error, Result};
+
+/// A component which isn't in the release.
+pub struct Missing {
+    pub package: String,
+    pub target: String,
+    /// Whether the release has to be stopped, rather than just warned about.
+    pub required: bool,
+}
+
+struct Rule {
+    channels: Option<Vec<String>>,
+    targets: Vec<String>,
+    required: Vec<String>,
+    warn: Vec<String>,
+}
+
+/// Returns every component required or wanted on `channel` which isn't among
+/// the `(package, target)` pairs `present`, sorted by target.
+pub fn missing(secrets: &Value, channel: &str, present: &BTreeSet<(String, String)>)
+    -> Result<Vec<Missing>>
+{
+    let mut missing = Vec::new();
+    for rule in rules(secrets)? {
+        if let Some(ref channels) = rule.channels {
+            if !channels.iter().any(|c| c == channel) {
+                continue
+            }
+        }
+        for target in rule.targets.iter() {
+            let wanted = rule.required.iter().map(|p| (p, true))
+                .chain(rule.warn.iter().map(|p| (p, false)));
+            for (package, required) in wanted {
+                if present.contains(&(package.clone(), target.clone())) {
+                    continue
+                }
+                missing.push(Missing {
+                    package: package.clone(),
+                    target: target.clone(),
+                    required,
+                });
+            }
+        }
+    }
+    missing.sort_by(|a, b| (&a.target, &a.package).cmp(&(&b.target, &b.package)));
+    missing.dedup_by(|a, b| {
+        if a.target == b.target && a.package == b.package {
+            b.required |= a.required;
+            true
+        } else {
+            false
+        }
+    });
+    Ok(missing)
+}
+
+fn rules(secrets: &Value) -> Result<Vec<Rule>> {
+    let config = match secrets.get("dist").and_then(|d| d.get("components")) {
+        Some(config) => config,
+        None => {
+            return Ok(vec![Rule {
+                channels: Some(vec!["nightly".to_string()]),
+                targets: vec!["x86_64-unknown-linux-gnu".to_string()],
+                required: strings(&["rustc", "rust-std", "cargo"]),
+                warn: strings(&["rustfmt", "rls", "clippy"]),
+            }])
+        }
+    };
+
+    let rules = match config.get("rules") {
+        Some(rules) => rules.as_array().ok_or_else(|| bad("`rules` must be an array"))?,
+        None => return Ok(Vec::new()),
+    };
+    rules.iter().map(|rule| {
+        let targets = match (rule.get("targets"), rule.get("tier").and_then(|t| t.as_str())) {
+            (Some(targets), None) => list(targets, "targets")?,
+            (None, Some(tier)) => {
+                let targets = config.get("tiers")
+                    .and_then(|t| t.get(tier))
+                    .ok_or_else(|| bad(&format!("unknown tier `{}`", tier)))?;
+                list(targets, "tiers")?
+            }
+            _ => return Err(bad("each rule needs exactly one of `targets` or `tier`")),
+        };
+        Ok(Rule {
+            channels: match rule.get("channels") {
+                Some(channels) => Some(list(channels, "channels")?),
+                None => None,
+            },
+            targets,
+            required: match rule.get("required") {
+                Some(required) => list(required, "required")?,
+                None => Vec::new(),
+            },
+            warn: match rule.get("warn") {
+                Some(warn) => list(warn, "warn")?,
+                None => Vec::new(),
+            },
+        })
+    }).collect()
+}
+
+fn list(value: &Value, what: &str) -> Result<Vec<String>> {
+    value.as_array()
+        .and_then(|a| a.iter().map(|v| v.as_str().map(|s| s.to_string())).collect())
+        .ok_or_else(|| bad(&format!("`{}` must be a list of strings", what)))
+}
+
+fn strings(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| s.to_string()).collect()
+}
+
+fn bad(msg: &str) -> Error {
+    Error::Config(format!("dist.components: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use errors::Error;
+    use super::missing;
+
+    const LINUX: &str = "x86_64-unknown-linux-gnu";
+    const WINDOWS: &str = "x86_64-pc-windows-msvc";
+
+    fn present(pairs: &[(&str, &str)]) -> BTreeSet<(String, String)> {
+        pairs.iter().map(|&(p, t)| (p.to_string(), t.to_string())).collect()
+    }
+
+    /// `(package, target, required)` for everything missing.
+    fn report(secrets: &str, channel: &str, pairs: &[(&str, &str)])
+        -> Vec<(String, String, bool)>
+    {
+        let secrets = secrets.parse().unwrap();
+        missing(&secrets, channel, &present(pairs)).unwrap()
+            .into_iter()
+            .map(|m| (m.package, m.target, m.required))
+            .collect()
+    }
+
+    fn row(package: &str, target: &str, required: bool) -> (String, String, bool) {
+        (package.to_string(), target.to_string(), required)
+    }
+
+    const CONFIG: &str = r#"
+        [dist.components.tiers]
+        tier-1 = ["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc"]
+
+        [[dist.components.rules]]
+        tier = "tier-1"
+        required = ["rustc", "cargo"]
+        warn = ["clippy"]
+
+        [[dist.components.rules]]
+        channels = ["nightly"]
+        targets = ["x86_64-pc-windows-msvc"]
+        required = ["clippy"]
+    "#;
+
+    #[test]
+    fn defaults_to_nightly_on_linux() {
+        let all = [("rustc", LINUX), ("rust-std", LINUX), ("cargo", LINUX),
+                   ("rustfmt", LINUX), ("rls", LINUX), ("clippy", LINUX)];
+        assert!(report("", "nightly", &all).is_empty());
+        assert_eq!(report("", "nightly", &[("rustc", LINUX), ("rust-std", LINUX)]), [
+            row("cargo", LINUX, true),
+            row("clippy", LINUX, false),
+            row("rls", LINUX, false),
+            row("rustfmt", LINUX, false),
+        ]);
+        assert!(report("", "beta", &[]).is_empty());
+    }
+
+    #[test]
+    fn reports_by_target() {
+        let present = [("rustc", LINUX), ("cargo", LINUX), ("rustc", WINDOWS)];
+        assert_eq!(report(CONFIG, "stable", &present), [
+            row("cargo", WINDOWS, true),
+            row("clippy", WINDOWS, false),
+            row("clippy", LINUX, false),
+        ]);
+    }
+
+    #[test]
+    fn required_wins_over_warn() {
+        // Clippy is only wanted on Windows in general, but required there on
+        // nightly, and is only reported once.
+        assert_eq!(report(CONFIG, "nightly", &[("rustc", LINUX), ("cargo", LINUX),
+                                               ("clippy", LINUX), ("rustc", WINDOWS),
+                                               ("cargo", WINDOWS)]), [
+            row("clippy", WINDOWS, true),
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let bad = [
+            "[dist.components]\nrules = 1",
+            "[[dist.components.rules]]\nrequired = [\"rustc\"]",
+            "[[dist.components.rules]]\ntier = \"tier-2\"",
+            "[[dist.components.rules]]\ntargets = [\"a\"]\ntier = \"tier-1\"",
+            "[[dist.components.rules]]\ntargets = \"a\"",
+            "[[dist.components.rules]]\ntargets = [\"a\"]\nchannels = [1]",
+        ];
+        for secrets in bad.iter() {
+            match missing(&secrets.parse().unwrap(), "stable", &present(&[])) {
+                Err(Error::Config(msg)) => assert!(msg.starts_with("dist.components: "), "{}", msg),
+                res => panic!("{:?} accepted {}", res.map(|m| m.len()), secrets),
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! What changed between the previous release of a channel and the new one:
+//! packages and targets which appeared or disappeared, and tarballs whose size
+//! moved by more than a threshold.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use serde_json::Value;
+
+use errors::Result;
+use {rust_version, write_json};
+
+pub struct Diff {
+    previous: Option<String>,
+    current: Option<String>,
+    added_packages: Vec<String>,
+    removed_packages: Vec<String>,
+    /// `(package, target)` pairs of packages in both releases.
+    added_targets: Vec<(String, String)>,
+    removed_targets: Vec<(String, String)>,
+    sizes: Vec<SizeChange>,
+}
+
+struct SizeChange {
+    package: String,
+    target: String,
+    before: u64,
+    after: u64,
+}
+
+impl Diff {
+    /// Compares two channel manifests, with the sizes of the files they list
+    /// given by file name in `previous_sizes` and `current_sizes`. Only size
+    /// changes of at least `threshold` percent are kept.
+    pub fn new(previous: &::toml::Value,
+               current: &::toml::Value,
+               previous_sizes: &HashMap<String, u64>,
+               current_sizes: &HashMap<String, u64>,
+               threshold: f64) -> Diff {
+        let before = tarballs(previous);
+        let after = tarballs(current);
+        let packages = |t: &HashMap<(String, String), String>| {
+            t.keys().map(|k| k.0.clone()).collect::<BTreeSet<_>>()
+        };
+        let (packages_before, packages_after) = (packages(&before), packages(&after));
+        let targets = |t: &HashMap<(String, String), String>| {
+            t.keys()
+                .filter(|k| packages_before.contains(&k.0) && packages_after.contains(&k.0))
+                .cloned()
+                .collect::<BTreeSet<_>>()
+        };
+        let (targets_before, targets_after) = (targets(&before), targets(&after));
+
+        let mut sizes = Vec::new();
+        for key in targets_before.intersection(&targets_after) {
+            let size_before = previous_sizes.get(&before[key]).cloned();
+            let size_after = current_sizes.get(&after[key]).cloned();
+            if let (Some(size_before), Some(size_after)) = (size_before, size_after) {
+                let change = if size_before == 0 {
+                    100.0
+                } else {
+                    (size_after as f64 - size_before as f64) / size_before as f64 * 100.0
+                };
+                if change.abs() >= threshold {
+                    sizes.push(SizeChange {
+                        package: key.0.clone(),
+                        target: key.1.clone(),
+                        before: size_before,
+                        after: size_after,
+                    });
+                }
+            }
+        }
+
+        Diff {
+            previous: version(previous),
+            current: version(current),
+            added_packages: packages_after.difference(&packages_before).cloned().collect(),
+            removed_packages: packages_before.difference(&packages_after).cloned().collect(),
+            added_targets: targets_after.difference(&targets_before).cloned().collect(),
+            removed_targets: targets_before.difference(&targets_after).cloned().collect(),
+            sizes,
+        }
+    }
+
+    pub fn print(&self) {
+        println!("changes since {}:", self.previous.as_deref().unwrap_or("the last release"));
+        for package in self.added_packages.iter() {
+            println!("  added package {}", package);
+        }
+        for package in self.removed_packages.iter() {
+            println!("  removed package {}", package);
+        }
+        for (package, target) in self.added_targets.iter() {
+            println!("  added {} for {}", package, target);
+        }
+        for (package, target) in self.removed_targets.iter() {
+            println!("  removed {} for {}", package, target);
+        }
+        for change in self.sizes.iter() {
+            println!("  {} for {} went from {} to {} bytes",
+                     change.package, change.target, change.before, change.after);
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let pairs = |pairs: &[(String, String)]| {
+            pairs.iter().map(|(package, target)| {
+                json!({ "package": package, "target": target })
+            }).collect::<Vec<_>>()
+        };
+        json!({
+            "previous_version": self.previous,
+            "new_version": self.current,
+            "added_packages": self.added_packages,
+            "removed_packages": self.removed_packages,
+            "added_targets": pairs(&self.added_targets),
+            "removed_targets": pairs(&self.removed_targets),
+            "size_changes": self.sizes.iter().map(|c| {
+                json!({
+                    "package": c.package,
+                    "target": c.target,
+                    "before": c.before,
+                    "after": c.after,
+                })
+            }).collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_json(path, &self.to_json())
+    }
+}
+
+/// Every available `(package, target)` in a manifest, mapped to the file name
+/// of its tarball, preferring the `.xz` one.
+fn tarballs(manifest: &::toml::Value) -> HashMap<(String, String), String> {
+    let mut tarballs = HashMap::new();
+    let pkgs = manifest.get("pkg").and_then(|p| p.as_table());
+    for (package, pkg) in pkgs.into_iter().flat_map(|p| p.iter()) {
+        let targets = pkg.get("target").and_then(|t| t.as_table());
+        for (target, info) in targets.into_iter().flat_map(|t| t.iter()) {
+            if info.get("available").and_then(|a| a.as_bool()) != Some(true) {
+                continue
+            }
+            let url = info.get("xz_url")
+                .or_else(|| info.get("url"))
+                .and_then(|u| u.as_str())
+                .unwrap_or("");
+            let name = &url[url.rfind('/').map_or(0, |i| i + 1)..];
+            tarballs.insert((package.clone(), target.clone()), name.to_string());
+        }
+    }
+    tarballs
+}
+
+fn version(manifest: &::toml::Value) -> Option<String> {
+    rust_version(manifest).ok().map(|v| v.to_string())
+}
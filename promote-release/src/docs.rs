@@ -0,0 +1,153 @@
+//! Extraction of the HTML documentation published to `/doc/$channel` from the
+//! `rust-docs` and `rustc-docs` tarballs of a release.
+
+use std::fs::{self, File};
+use std::path::{Component, Path};
+
+use flate2::read::GzDecoder;
+use tar::Archive;
+
+use errors::Result;
+
+/// Picks the target whose docs to publish out of those `available`: the first
+/// of `preferred` which is there, or else the first one available at all.
+pub fn pick_target(preferred: &[String], available: &[String]) -> Option<String> {
+    preferred.iter()
+        .find(|t| available.contains(t))
+        .or_else(|| available.iter().min())
+        .cloned()
+}
+
+/// How many bytes unpacking `inner` out of `tarball` takes up, without
+/// unpacking anything.
+pub fn unpacked_size(tarball: &Path, inner: &str) -> Result<u64> {
+    let mut archive = Archive::new(GzDecoder::new(t!(File::open(tarball))));
+    let mut size = 0;
+    for entry in t!(archive.entries()) {
+        let entry = t!(entry);
+        if t!(entry.path()).starts_with(inner) {
+            size += entry.size();
+        }
+    }
+    Ok(size)
+}
+
+/// Unpacks everything underneath the directory `inner` of the gzipped tarball
+/// `tarball` into `dst`, returning whether there was anything there.
+pub fn unpack(tarball: &Path, inner: &str, dst: &Path) -> Result<bool> {
+    let mut archive = Archive::new(GzDecoder::new(t!(File::open(tarball))));
+    let mut found = false;
+    for entry in t!(archive.entries()) {
+        let mut entry = t!(entry);
+        let path = t!(entry.path()).into_owned();
+        let relative = match path.strip_prefix(inner) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        // Never write outside of `dst`, whatever the tarball contains.
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            continue
+        }
+        found = true;
+        if relative.as_os_str().is_empty() {
+            continue
+        }
+        let out = dst.join(relative);
+        if let Some(parent) = out.parent() {
+            t!(fs::create_dir_all(parent));
+        }
+        t!(entry.unpack(&out));
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use tar::{Builder, Header};
+
+    use tests::TempDir;
+    use super::{pick_target, unpack, unpacked_size};
+
+    const HTML: &str = "rustc-docs-nightly/rustc-docs/share/doc/rust/html";
+
+    /// Writes a tarball of `files` given as `(path, contents)`, with the paths
+    /// written as they are, `..` and all, so they have to fit in the header.
+    fn tarball(path: &Path, files: &[&(&str, &str)]) {
+        let mut builder = Builder::new(GzEncoder::new(fs::File::create(path).unwrap(),
+                                                      Compression::fast()));
+        for &&(name, contents) in files {
+            let mut header = Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn html(path: &str) -> String {
+        format!("{}/{}", HTML, path)
+    }
+
+    #[test]
+    fn never_unpacks_outside_the_destination() {
+        let dir = TempDir::new("docs-escape");
+        let gz = dir.path().join("docs.tar.gz");
+        let (index, up, sideways) = (html("index.html"),
+                                     html("../../../../../../escaped.html"),
+                                     html("std/../../sideways.html"));
+        tarball(&gz, &[&(&index, "index"), &(&up, "up"), &(&sideways, "sideways")]);
+        let dst = dir.path().join("out/docs");
+        assert!(unpack(&gz, HTML, &dst).unwrap());
+        assert_eq!(fs::read_to_string(dst.join("index.html")).unwrap(), "index");
+        let mut unpacked = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        unpacked.sort();
+        assert_eq!(unpacked, ["docs.tar.gz", "out"]);
+        assert_eq!(fs::read_dir(&dst).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn finds_rustc_docs_with_or_without_the_subdirectory() {
+        let dir = TempDir::new("docs-rustc");
+        let inner = format!("{}/rustc", HTML);
+
+        // Newer tarballs keep the rustc docs in a `rustc` subdirectory.
+        let gz = dir.path().join("new.tar.gz");
+        let (index, std) = (html("rustc/index.html"), html("std/index.html"));
+        tarball(&gz, &[&(&index, "rustc"), &(&std, "std")]);
+        let dst = dir.path().join("new");
+        assert!(unpack(&gz, &inner, &dst).unwrap());
+        assert_eq!(fs::read_to_string(dst.join("index.html")).unwrap(), "rustc");
+        assert!(!dst.join("std").exists());
+        assert_eq!(unpacked_size(&gz, &inner).unwrap(), 5);
+
+        // Older ones have them right in the HTML directory, which is what's
+        // unpacked when the subdirectory isn't there.
+        let gz = dir.path().join("old.tar.gz");
+        let index = html("index.html");
+        tarball(&gz, &[&(&index, "rustc")]);
+        let dst = dir.path().join("old");
+        assert!(!unpack(&gz, &inner, &dst).unwrap());
+        assert!(!dst.exists());
+        assert!(unpack(&gz, HTML, &dst).unwrap());
+        assert_eq!(fs::read_to_string(dst.join("index.html")).unwrap(), "rustc");
+    }
+
+    #[test]
+    fn picks_the_preferred_target() {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let available = strings(&["x86_64-unknown-linux-gnu", "aarch64-apple-darwin"]);
+        let preferred = strings(&["x86_64-pc-windows-msvc", "x86_64-unknown-linux-gnu"]);
+        assert_eq!(pick_target(&preferred, &available).unwrap(), "x86_64-unknown-linux-gnu");
+        assert_eq!(pick_target(&[], &available).unwrap(), "aarch64-apple-darwin");
+        assert_eq!(pick_target(&preferred, &[]), None);
+    }
+}
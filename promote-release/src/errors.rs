@@ -0,0 +1,122 @@
+//! Everything that can go wrong during a promotion, grouped by what whoever
+//! is running us should do about it.
+
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+use std::result;
+
+use curl;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The secrets or the command line are missing something or are invalid.
+    Config(String),
+    /// Another invocation for the same work directory is still running.
+    Locked,
+    /// The artifacts for this rev aren't ready to be released yet, for
+    /// example because CI hasn't uploaded them or the branch is awaiting the
+    /// PR which changes its release channel. Trying again later should work.
+    NotReady(String),
+    /// The artifacts are there, but some required components are missing.
+    MissingComponents(Vec<String>),
+    /// A channel manifest couldn't be understood.
+    Manifest(String),
+    /// An external command couldn't be run or exited unsuccessfully.
+    Command(String),
+    /// An HTTP request, e.g. fetching the current manifest, failed.
+    Network(String),
+    /// Uploading to or downloading from storage failed, including because
+    /// the credentials were rejected.
+    Storage(String),
+    /// A local filesystem operation failed.
+    Io(String, io::Error),
+    /// An artifact couldn't be signed, or a signature didn't verify.
+    Signature(String),
+    /// What's being served after publishing isn't what we released.
+    Verification(String),
+    /// The release would replace what's live with an older version.
+    Downgrade(String),
+    /// Invalidating the CDN failed or didn't finish in time.
+    Invalidation(String),
+    /// There isn't enough free disk space in the work directory.
+    DiskSpace(String),
+    /// Something that should be impossible happened, i.e. a bug.
+    Internal(String),
+}
+
+impl Error {
+    /// The process exit code for this error. These are stable so that
+    /// wrapper scripts can decide whether to page someone or retry later:
+    ///
+    /// * 2 - configuration error
+    /// * 3 - another promotion is still running
+    /// * 4 - artifacts aren't ready yet, try again later
+    /// * 5 - required components are missing
+    /// * 6 - invalid channel manifest
+    /// * 7 - an external command failed
+    /// * 8 - network error
+    /// * 9 - storage error
+    /// * 10 - local I/O error
+    /// * 11 - signing failed or a signature is invalid
+    /// * 12 - the published release doesn't match what was uploaded
+    /// * 13 - the release is older than what's live
+    /// * 14 - the CDN couldn't be invalidated
+    /// * 15 - not enough disk space
+    /// * 16 - internal error, which is a bug, including panics
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Config(_) => 2,
+            Error::Locked => 3,
+            Error::NotReady(_) => 4,
+            Error::MissingComponents(_) => 5,
+            Error::Manifest(_) => 6,
+            Error::Command(_) => 7,
+            Error::Network(_) => 8,
+            Error::Storage(_) => 9,
+            Error::Io(..) => 10,
+            Error::Signature(_) => 11,
+            Error::Verification(_) => 12,
+            Error::Downgrade(_) => 13,
+            Error::Invalidation(_) => 14,
+            Error::DiskSpace(_) => 15,
+            Error::Internal(_) => 16,
+        }
+    }
+
+    pub fn command(cmd: &dyn fmt::Debug, status: ExitStatus, output: &str) -> Error {
+        Error::Command(format!("{:?} failed with {}{}", cmd, status, output))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref msg) => write!(f, "configuration error: {}", msg),
+            Error::Locked => write!(f, "another promotion is already running"),
+            Error::NotReady(ref msg) => write!(f, "not ready to release: {}", msg),
+            Error::MissingComponents(ref missing) => {
+                write!(f, "missing components: {}", missing.join(", "))
+            }
+            Error::Manifest(ref msg) => write!(f, "invalid manifest: {}", msg),
+            Error::Command(ref msg) => write!(f, "command failed: {}", msg),
+            Error::Network(ref msg) => write!(f, "network error: {}", msg),
+            Error::Storage(ref msg) => write!(f, "storage error: {}", msg),
+            Error::Io(ref what, ref e) => write!(f, "{} failed: {}", what, e),
+            Error::Signature(ref msg) => write!(f, "signature error: {}", msg),
+            Error::Verification(ref msg) => write!(f, "verification failed: {}", msg),
+            Error::Downgrade(ref msg) => write!(f, "refusing to downgrade: {}", msg),
+            Error::Invalidation(ref msg) => write!(f, "invalidation failed: {}", msg),
+            Error::DiskSpace(ref msg) => write!(f, "not enough disk space: {}", msg),
+            Error::Internal(ref msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl From<curl::Error> for Error {
+    fn from(e: curl::Error) -> Error {
+        Error::Network(e.to_string())
+    }
+}
@@ -0,0 +1,190 @@
+//! SHA-256 hashes and S3 ETags of release artifacts.
+//!
+//! Artifacts add up to gigabytes, so hashes computed as a side effect of
+//! writing a file (e.g. while recompressing it) are remembered and reused by
+//! everything which needs them later instead of reading the file again. The
+//! ETag S3 would give a file is worked out in the same pass, to tell which
+//! files differ from what's already published.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use errors::Result;
+use hex;
+use s3::PART_SIZE;
+
+pub struct Hashes {
+    known: Mutex<HashMap<PathBuf, Digests>>,
+}
+
+/// Everything worked out about a file's contents while reading or writing
+/// it.
+#[derive(Clone)]
+pub struct Digests {
+    pub sha256: String,
+    /// The ETag S3 gives the file once `s3::S3` has uploaded it.
+    pub etag: String,
+}
+
+impl Hashes {
+    pub fn new() -> Hashes {
+        Hashes { known: Mutex::new(HashMap::new()) }
+    }
+
+    /// Records that `path` currently has the digests `digests`.
+    pub fn insert(&self, path: &Path, digests: Digests) {
+        self.known.lock().unwrap().insert(path.to_path_buf(), digests);
+    }
+
+    /// Forgets everything, for when the files are about to be replaced.
+    pub fn clear(&self) {
+        self.known.lock().unwrap().clear();
+    }
+
+    /// Returns the hash of `path`, only reading it if it's not known yet.
+    pub fn sha256(&self, path: &Path) -> Result<String> {
+        Ok(self.digests(path)?.sha256)
+    }
+
+    /// Returns the S3 ETag of `path`, only reading it if it's not known yet.
+    pub fn etag(&self, path: &Path) -> Result<String> {
+        Ok(self.digests(path)?.etag)
+    }
+
+    fn digests(&self, path: &Path) -> Result<Digests> {
+        if let Some(digests) = self.known.lock().unwrap().get(path) {
+            return Ok(digests.clone())
+        }
+        let digests = digests(path)?;
+        self.insert(path, digests.clone());
+        Ok(digests)
+    }
+}
+
+pub fn digests(path: &Path) -> Result<Digests> {
+    let mut file = Hashing::new(t!(File::open(path)));
+    t!(io::copy(&mut file, &mut io::sink()));
+    Ok(file.finish().1)
+}
+
+/// A reader or writer which hashes everything passing through it.
+pub struct Hashing<T> {
+    inner: T,
+    sha256: Sha256,
+    len: u64,
+    /// The MD5 of the part of a multipart upload currently passing through,
+    /// and those of the parts before it.
+    part: Md5,
+    parts: Vec<u8>,
+    part_size: u64,
+}
+
+impl<T> Hashing<T> {
+    pub fn new(inner: T) -> Hashing<T> {
+        Hashing {
+            inner,
+            sha256: Sha256::new(),
+            len: 0,
+            part: Md5::new(),
+            parts: Vec::new(),
+            part_size: PART_SIZE,
+        }
+    }
+
+    /// Number of bytes which passed through so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn finish(mut self) -> (T, Digests) {
+        self.parts.extend_from_slice(&self.part.result());
+        // Files larger than a part are uploaded in parts, see
+        // `S3::upload_multipart`.
+        let etag = match self.parts.len() / 16 {
+            1 => hex(&self.parts),
+            parts => format!("{}-{}", hex(&Md5::digest(&self.parts)), parts),
+        };
+        (self.inner, Digests { sha256: hex(&self.sha256.result()), etag })
+    }
+
+    fn update(&mut self, mut buf: &[u8]) {
+        self.sha256.input(buf);
+        while !buf.is_empty() {
+            // A part is only finished once there's more to come, so that a
+            // file of exactly one part isn't taken for two.
+            let offset = self.len % self.part_size;
+            if offset == 0 && self.len > 0 {
+                let part = mem::replace(&mut self.part, Md5::new());
+                self.parts.extend_from_slice(&part.result());
+            }
+            let n = buf.len().min((self.part_size - offset) as usize);
+            self.part.input(&buf[..n]);
+            self.len += n as u64;
+            buf = &buf[n..];
+        }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use md5::{Digest, Md5};
+
+    use hex;
+    use super::Hashing;
+
+    fn etag(data: &[u8], part_size: u64) -> String {
+        let mut hashing = Hashing::new(io::sink());
+        hashing.part_size = part_size;
+        // Split the writes up differently from the parts.
+        for chunk in data.chunks(3) {
+            io::copy(&mut &chunk[..], &mut hashing).unwrap();
+        }
+        hashing.finish().1.etag
+    }
+
+    #[test]
+    fn etags() {
+        assert_eq!(etag(b"hello", 8), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(etag(b"", 8), "d41d8cd98f00b204e9800998ecf8427e");
+        // Exactly one part is still uploaded as a single object.
+        assert_eq!(etag(b"hello", 5), "5d41402abc4b2a76b9719d911017c592");
+    }
+
+    #[test]
+    fn multipart_etags() {
+        let mut parts = Vec::new();
+        for part in [&b"hello wo"[..], b"rld"].iter() {
+            parts.extend_from_slice(&Md5::digest(part));
+        }
+        assert_eq!(etag(b"hello world", 8), format!("{}-2", hex(&Md5::digest(&parts))));
+    }
+}
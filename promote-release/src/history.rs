@@ -0,0 +1,113 @@
+//! The machine-readable list of everything released on a channel, published
+//! as `channel-history-$channel.json` next to the channel manifests:
+//!
+//! ```json
+//! {
+//!   "channel": "nightly",
+//!   "releases": [
+//!     {
+//!       "date": "2019-12-16",
+//!       "channel": "nightly",
+//!       "version": "1.42.0-nightly (0d2817a43 2019-12-16)",
+//!       "rev": "0d2817a439a8f2b2b0b0a2a3c6e0c5b8b6a5e3d1",
+//!       "manifest_url": "https://static.rust-lang.org/dist/2019-12-16/channel-rust-nightly.toml",
+//!       "manifest_sha256": "..."
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Releases are ordered by date, oldest first.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde_json::{self, Value};
+
+use errors::{Error, Result};
+use write_json;
+
+pub struct Entry {
+    pub date: String,
+    pub channel: String,
+    pub version: String,
+    pub rev: String,
+    pub manifest_url: String,
+    pub manifest_sha256: String,
+}
+
+pub struct History {
+    channel: String,
+    entries: Vec<Entry>,
+}
+
+/// Name of the history file of `channel`.
+pub fn file_name(channel: &str) -> String {
+    format!("channel-history-{}.json", channel)
+}
+
+impl History {
+    pub fn new(channel: &str) -> History {
+        History { channel: channel.to_string(), entries: Vec::new() }
+    }
+
+    /// Reads the history of `channel` from `path`, which may not exist yet.
+    pub fn load(path: &Path, channel: &str) -> Result<History> {
+        let mut history = History::new(channel);
+        if !path.exists() {
+            return Ok(history)
+        }
+        let mut contents = String::new();
+        t!(t!(File::open(path)).read_to_string(&mut contents));
+        let bad = |why: &str| Error::Manifest(format!("{}: {}", path.display(), why));
+        let json: Value = serde_json::from_str(&contents).map_err(|e| bad(&e.to_string()))?;
+        let releases = json["releases"].as_array().ok_or_else(|| bad("no releases"))?;
+        for release in releases {
+            let field = |name: &str| {
+                release[name].as_str()
+                    .map(|s| s.to_string())
+                    .ok_or_else(|| bad(&format!("release without a `{}`", name)))
+            };
+            history.entries.push(Entry {
+                date: field("date")?,
+                channel: field("channel")?,
+                version: field("version")?,
+                rev: field("rev")?,
+                manifest_url: field("manifest_url")?,
+                manifest_sha256: field("manifest_sha256")?,
+            });
+        }
+        Ok(history)
+    }
+
+    /// Adds `entry`, replacing an earlier record of the same release, e.g.
+    /// from a run which was resumed.
+    pub fn add(&mut self, entry: Entry) {
+        self.entries.retain(|e| (&e.date, &e.rev) != (&entry.date, &entry.rev));
+        self.entries.push(entry);
+        // Stable, so releases on the same day stay in the order they happened.
+        self.entries.sort_by(|a, b| a.date.cmp(&b.date));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = json!({
+            "channel": self.channel,
+            "releases": self.entries.iter().map(|e| {
+                json!({
+                    "date": e.date,
+                    "channel": e.channel,
+                    "version": e.version,
+                    "rev": e.rev,
+                    "manifest_url": e.manifest_url,
+                    "manifest_sha256": e.manifest_sha256,
+                })
+            }).collect::<Vec<_>>(),
+        });
+        write_json(path, &json)
+    }
+}
@@ -1,59 +1,337 @@
+extern crate base64;
+extern crate crossbeam_utils;
 extern crate curl;
 extern crate flate2;
 extern crate fs2;
+extern crate hmac;
+extern crate md5;
 extern crate rand;
 #[macro_use]
 extern crate serde_json;
+extern crate sha2;
 extern crate tar;
+extern crate time;
 extern crate toml;
 extern crate xz2;
 
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{PathBuf, Path};
-use std::process::Command;
+use std::process::{self, Command};
+use std::thread;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use curl::easy::Easy;
+use crossbeam_utils::thread::scope;
+use curl::easy::{Easy, List};
 use fs2::FileExt;
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+
+use errors::{Error, Result};
+use hashes::{Hashes, Hashing};
+use history::History;
+use manifest::package_version;
+use report::{Outcome, Report};
+use state::Phase;
+use cache::{Cache, Claim};
+use storage::Storage;
+use version::{Pre, Version};
 
 macro_rules! t {
     ($e:expr) => (match $e {
         Ok(e) => e,
-        Err(e) => panic!("{} failed with {:?}", stringify!($e), e),
+        Err(e) => return Err(::errors::Error::Io(stringify!($e).to_string(), e)),
     })
 }
 
+mod cache;
+mod cloudfront;
+mod components;
+mod diff;
+mod docs;
+mod errors;
+mod hashes;
+mod history;
+mod manifest;
+mod pgp;
+mod report;
+mod retention;
+mod s3;
+mod state;
+mod storage;
+mod version;
+#[cfg(test)]
+mod tests;
+
 struct Context {
+    task: Task,
     work: PathBuf,
     release: String,
     handle: Easy,
 	secrets: toml::Value,
     date: String,
-    current_version: Option<String>,
+    current_version: Option<Version>,
+    dry_run: bool,
+    /// The rev to release instead of the tip of the channel's branch.
+    rev: Option<String>,
+    /// The date of an archived release to publish again instead.
+    archived: Option<String>,
+    /// The stable version to publish the docs of, for `Task::Docs`.
+    docs_version: Option<String>,
+    storage: Box<dyn Storage>,
+    report: Report,
+    hashes: Hashes,
+    /// Keeps the rev being released in the download cache while we use it.
+    claim: Option<Claim>,
+    /// Fails the release right after this phase, to test resuming it.
+    #[cfg(test)]
+    stop_after: Option<Phase>,
+}
+
+/// How often `Context::wait_for_lock` tries to take the lock again.
+const LOCK_POLL: Duration = Duration::from_secs(1);
+
+/// The order channels given together are processed in, so the rarer and more
+/// important releases aren't held up by the others.
+const CHANNELS: &[&str] = &["stable", "beta", "nightly", "dev"];
+
+/// What we were asked to do, selected by an optional first argument.
+#[derive(Clone, Copy, PartialEq)]
+enum Task {
+    Release,
+    Rollback,
+    Gc,
+    History,
+    Docs,
 }
 
 // Called as:
 //
-//  $prog work/dir release-channel path/to/secrets.toml
+//  $prog work/dir release-channel path/to/secrets.toml [rev | date]
+//
+// The release channel is one of `nightly`, `beta` or `stable`, which release
+// the tip of their branch, or `dev`, which releases the given `rev` to the
+// locations configured in `[dist.dev]` instead, e.g. to publish release
+// candidates for testing.
+//
+// Several channels can be given at once as e.g. `nightly,beta`, which are then
+// processed one after the other in the order of `CHANNELS`, carrying on with
+// the rest if one fails. Each channel has its own lock and scratch space in
+// the work dir, so several invocations can share one, along with the checkout
+// of rust-lang/rust and the cache of downloaded artifacts. Only one of them can
+// use the checkout at a time, and the others wait their turn for up to
+// `dist.repo-lock-timeout` seconds.
+//
+// Giving a full commit hash releases that commit instead of the tip of the
+// branch. Giving a date (YYYY-MM-DD) instead publishes the release archived on
+// that date as the channel again, e.g. to roll back a broken nightly.
+//
+// Rolling back is also available as its own command:
+//
+//  $prog rollback work/dir release-channel path/to/secrets.toml [date]
+//
+// which without a date lists the dates archived for the channel, and with one
+// checks that the archive from that date is complete before publishing it.
+//
+//  $prog gc work/dir release-channel path/to/secrets.toml
+//
+// deletes the channel's releases which have outlived the retention policy in
+// `[dist.retention]` from the archive, printing what's kept and why first.
+//
+// Each release is also recorded in `channel-history-$channel.json` next to the
+// channel manifests, see `history.rs`, which
+//
+//  $prog history work/dir release-channel path/to/secrets.toml
+//
+// rebuilds from scratch out of the archive.
+//
+//  $prog docs work/dir release-channel path/to/secrets.toml [version]
+//
+// publishes the docs of the channel's live release again without releasing
+// anything, or for `stable`, the docs of an older version given as `1.40.0`.
+//
+// Setting `PROMOTE_RELEASE_DRY_RUN` runs every step of the release except for
+// the ones which publish something, printing what would have been uploaded
+// and invalidated instead.
+//
+// Progress through a release is recorded in the work dir, and a later run for
+// the same rev resumes after the last phase which completed. Setting
+// `PROMOTE_RELEASE_RESTART` discards that progress and starts from scratch.
+//
+// Every run, whether it releases, skips or fails, writes a JSON report to
+// `report-$channel.json` in the work dir.
+//
+// The last line printed summarizes how the run went, and failures exit with a
+// code identifying what kind of failure it was, see `Error::exit_code`.
 fn main() {
-    let mut secrets = String::new();
-    t!(t!(File::open(env::args().nth(3).unwrap())).read_to_string(&mut secrets));
-
-    Context {
-        work: t!(env::current_dir()).join(env::args_os().nth(1).unwrap()),
-        release: env::args().nth(2).unwrap(),
-        secrets: t!(secrets.parse()),
-        handle: Easy::new(),
-        date: output(Command::new("date").arg("+%Y-%m-%d")).trim().to_string(),
-        current_version: None,
-    }.run()
+    let contexts = match Context::all() {
+        Ok(contexts) => contexts,
+        Err(e) => {
+            println!("promote-release: error: {}", e);
+            process::exit(e.exit_code());
+        }
+    };
+    // With several channels, the first failure decides the exit code.
+    let several = contexts.len() > 1;
+    let mut failed = None;
+    for mut cx in contexts {
+        let release = cx.release.clone();
+        match cx.run() {
+            Ok(Outcome::Released) => println!("promote-release: released {}", release),
+            Ok(Outcome::Skipped(reason)) => {
+                println!("promote-release: skipped {}: {}", release, reason)
+            }
+            Ok(Outcome::Collected { files, bytes }) => {
+                println!("promote-release: deleted {} files ({} bytes) archived for {}",
+                         files, bytes, release)
+            }
+            Ok(Outcome::DocsPublished) => {
+                println!("promote-release: published docs for {}", release)
+            }
+            Ok(Outcome::Indexed(releases)) => {
+                println!("promote-release: rebuilt history of {} with {} releases",
+                         release, releases)
+            }
+            Ok(Outcome::Failed(_)) => unreachable!(),
+            Err(e) => {
+                if several {
+                    println!("promote-release: error in {}: {}", release, e);
+                } else {
+                    println!("promote-release: error: {}", e);
+                }
+                failed.get_or_insert(e.exit_code());
+            }
+        }
+    }
+    if let Some(code) = failed {
+        process::exit(code);
+    }
+}
+
+fn usage() -> Error {
+    Error::Config("usage: promote-release [rollback | gc | history | docs] work/dir \
+                   release-channel[,release-channel...] path/to/secrets.toml \
+                   [rev | date | version]".to_string())
 }
 
 impl Context {
-    fn run(&mut self) {
-        let _lock = self.lock();
-        self.update_repo();
+    /// Creates a context for each of the channels given on the command line,
+    /// in the order they're to be processed in.
+    fn all() -> Result<Vec<Context>> {
+        let task = match env::args_os().nth(1) {
+            Some(ref arg) if arg == "rollback" => Task::Rollback,
+            Some(ref arg) if arg == "gc" => Task::Gc,
+            Some(ref arg) if arg == "history" => Task::History,
+            Some(ref arg) if arg == "docs" => Task::Docs,
+            _ => Task::Release,
+        };
+        let offset = if task == Task::Release { 0 } else { 1 };
+        let work = env::args_os().nth(offset + 1).ok_or_else(usage)?;
+        let channels = env::args().nth(offset + 2).ok_or_else(usage)?;
+        let secrets = env::args().nth(offset + 3).ok_or_else(usage)?;
+        let arg = env::args().nth(offset + 4);
+
+        let mut channels = channels.split(',')
+            .filter(|c| !c.is_empty())
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|c| CHANNELS.iter().position(|o| o == c).unwrap_or(CHANNELS.len()));
+        channels.dedup();
+        if channels.is_empty() {
+            return Err(usage())
+        }
+        if channels.len() > 1 && arg.is_some() {
+            return Err(Error::Config("a rev, date or version can only be given for a \
+                                      single channel".to_string()))
+        }
+
+        // Not being able to read the secrets is a problem with how we were
+        // set up, like not being able to parse them.
+        let contents = fs::read_to_string(&secrets).map_err(|e| {
+            Error::Config(format!("failed to read {}: {}", secrets, e))
+        })?;
+        let secrets = contents.parse::<toml::Value>().map_err(|e| {
+            Error::Config(format!("failed to parse {}: {}", secrets, e))
+        })?;
+        let work = t!(env::current_dir()).join(work);
+        let date = output(Command::new("date").arg("+%Y-%m-%d"))?.trim().to_string();
+
+        channels.into_iter().map(|release| {
+            Context::new(task, work.clone(), release, secrets.clone(), arg.clone(), date.clone())
+        }).collect()
+    }
+
+    fn new(task: Task,
+           work: PathBuf,
+           release: String,
+           secrets: toml::Value,
+           arg: Option<String>,
+           date: String) -> Result<Context> {
+        let mut docs_version = None;
+        let (rev, archived) = match arg {
+            Some(arg) => {
+                if task == Task::Docs {
+                    match Version::parse(&arg) {
+                        Ok(ref v) if v.pre == Pre::Stable && v.commit.is_none() => {}
+                        _ => return Err(Error::Config(format!("`{}` is not a stable \
+                                                               version", arg))),
+                    }
+                    if release != "stable" {
+                        return Err(Error::Config("only stable docs can be published \
+                                                  for a version".to_string()))
+                    }
+                    docs_version = Some(arg);
+                    (None, None)
+                } else if task == Task::Gc || task == Task::History {
+                    return Err(usage())
+                } else if is_date(&arg) {
+                    (None, Some(arg))
+                } else if task == Task::Rollback {
+                    return Err(Error::Config(format!("`{}` is not a date", arg)))
+                } else if arg.len() == 40 && arg.chars().all(|c| c.is_ascii_hexdigit()) {
+                    (Some(arg), None)
+                } else {
+                    return Err(Error::Config(format!("`{}` is neither a full commit hash \
+                                                      nor a date", arg)))
+                }
+            }
+            None => (None, None),
+        };
+        if release == "dev" && task == Task::Release && rev.is_none() && archived.is_none() {
+            return Err(Error::Config("dev releases need a rev to release".to_string()))
+        }
+
+        Ok(Context {
+            task,
+            work,
+            release,
+            storage: storage::from_secrets(&secrets)?,
+            secrets,
+            handle: Easy::new(),
+            date,
+            current_version: None,
+            dry_run: env::var_os("PROMOTE_RELEASE_DRY_RUN").is_some(),
+            rev,
+            archived,
+            docs_version,
+            report: Report::new(),
+            hashes: Hashes::new(),
+            claim: None,
+            #[cfg(test)]
+            stop_after: None,
+        })
+    }
+
+    fn run(&mut self) -> Result<Outcome> {
+        if self.task == Task::Rollback && self.archived.is_none() {
+            self.list_archives()?;
+            return Ok(skip("no date given to roll back to"))
+        }
 
         let override_var = env::var("PROMOTE_RELEASE_OVERRIDE_BRANCH");
         let branch = if let Ok(branch) = override_var.as_ref() {
@@ -63,29 +341,91 @@ impl Context {
                 "nightly" => "master",
                 "beta" => "beta",
                 "stable" => "stable",
-                _ => panic!("unknown release: {}", self.release),
+                // Only ever released from an explicit rev.
+                "dev" => "",
+                _ => {
+                    return Err(Error::Config(format!("unknown release: {}", self.release)))
+                }
+            }
+        };
+
+        let _lock = self.lock(&self.release)?;
+
+        // Make sure a report is written even if the release panics, and then
+        // fail like any other error would, so other channels are still
+        // released.
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            match (self.task, self.archived.clone()) {
+                (Task::Gc, _) => self.gc(),
+                (Task::History, _) => self.rebuild_history(),
+                (Task::Docs, _) => self.republish_docs(),
+                (_, Some(date)) => self.republish(&date),
+                (_, None) => self.do_release(branch),
             }
+        }));
+        let res = res.unwrap_or_else(|payload| {
+            let msg = payload.downcast_ref::<String>().cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Err(Error::Internal(format!("panicked: {}", msg)))
+        });
+        let outcome = match res {
+            Ok(ref outcome) => outcome,
+            Err(ref e) => &Outcome::Failed(e.to_string()),
         };
-        self.do_release(branch);
+        self.claim = None;
+        let path = self.work.join(format!("report-{}.json", self.release));
+        self.report.write(&path, &self.release, self.dry_run, outcome);
+        res
     }
 
-    /// Locks execution of concurrent invocations of this script in case one
-    /// takes a long time to run. The call to `try_lock_exclusive` will fail if
-    /// the lock is held already
-    fn lock(&mut self) -> File {
+    /// Locks execution of concurrent invocations of this script for `name`,
+    /// a channel or the shared checkout, in case one takes a long time to
+    /// run. The call to `try_lock_exclusive` will fail if the lock is held
+    /// already
+    fn lock(&self, name: &str) -> Result<File> {
         t!(fs::create_dir_all(&self.work));
         let file = t!(OpenOptions::new()
                             .read(true)
                             .write(true)
                             .create(true)
-                            .open(self.work.join(".lock")));
-        t!(file.try_lock_exclusive());
-        file
+                            .open(self.work.join(format!(".lock-{}", name))));
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(file),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => Err(Error::Locked),
+            Err(e) => Err(Error::Io("locking the work directory".to_string(), e)),
+        }
+    }
+
+    /// Like `lock`, but waits up to `dist.repo-lock-timeout` seconds for
+    /// whoever holds the lock to release it. Channels are released on
+    /// different schedules, so their runs overlap while they share one
+    /// checkout, and giving up straight away would fail a release that only
+    /// had to wait for another one's `x.py`.
+    fn wait_for_lock(&self, name: &str) -> Result<File> {
+        let timeout = self.secrets.get("dist")
+            .and_then(|d| d.get("repo-lock-timeout"))
+            .and_then(|t| t.as_integer())
+            .unwrap_or(7200);
+        let timeout = Duration::from_secs(timeout.max(0) as u64);
+        let start = Instant::now();
+        loop {
+            match self.lock(name) {
+                Err(Error::Locked) if start.elapsed() < timeout => {
+                    if start.elapsed() < LOCK_POLL {
+                        println!("waiting up to {}s for another promotion to release the \
+                                  {} lock", timeout.as_secs(), name);
+                    }
+                    thread::sleep(LOCK_POLL);
+                }
+                res => return res,
+            }
+        }
     }
 
     /// Update the rust repository we have cached, either cloning a fresh one or
     /// fetching remote references
-    fn update_repo(&mut self) {
+    fn update_repo(&mut self) -> Result<()> {
         // Clone/update the repo
         let dir = self.rust_dir();
         if dir.is_dir() {
@@ -93,39 +433,79 @@ impl Context {
             run(Command::new("git")
                         .arg("fetch")
                         .arg("origin")
-                        .current_dir(&dir));
+                        .current_dir(&dir))
         } else {
             println!("cloning");
             run(Command::new("git")
                         .arg("clone")
                         .arg("https://github.com/rust-lang/rust")
-                        .arg(&dir));
+                        .arg(&dir))
         }
     }
 
-    /// Does a release for the `branch` specified.
-    fn do_release(&mut self, branch: &str) {
+    /// Does a release for the `branch` specified, or of the rev given on the
+    /// command line.
+    fn do_release(&mut self, branch: &str) -> Result<Outcome> {
         // Learn the precise rev of the remote branch, this'll guide what we
-        // download.
-        let rev = output(Command::new("git")
-                                 .arg("rev-parse")
-                                 .arg(format!("origin/{}", branch))
-                                 .current_dir(&self.rust_dir()));
+        // download. Only `x.py` needs a checkout, and then the rev has to be
+        // one it has, otherwise asking GitHub is enough.
+        let rev = if let Some(rev) = self.rev.clone() {
+            rev
+        } else if self.use_xpy()? {
+            let _repo_lock = self.wait_for_lock("rust")?;
+            self.update_repo()?;
+            output(Command::new("git")
+                           .arg("rev-parse")
+                           .arg(format!("origin/{}", branch))
+                           .current_dir(&self.rust_dir()))?
+        } else {
+            self.branch_head(branch)?
+        };
         let rev = rev.trim();
         println!("{} rev is {}", self.release, rev);
+        self.report.rev = Some(rev.to_string());
+        if self.dry_run {
+            println!("dry run, nothing will be published");
+        }
 
         // Download the current live manifest for the channel we're releasing.
         // Through that we learn the current version of the release.
-        let manifest = self.download_manifest();
-        let previous_version = manifest["pkg"]["rust"]["version"]
-                                       .as_str()
-                                       .expect("rust version not a string");
-        println!("previous version: {}", previous_version);
+        // The dev channel may not have been released to before.
+        let previous = match self.download_manifest() {
+            Ok(manifest) => Some(manifest),
+            Err(Error::Network(ref msg)) if self.release == "dev" => {
+                println!("no previous dev release: {}", msg);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        let previous_version = match previous {
+            Some(ref manifest) => Some(rust_version(manifest)?),
+            None => None,
+        };
+        if let Some(ref previous_version) = previous_version {
+            println!("previous version: {}", previous_version);
+            self.report.previous_version = Some(previous_version.to_string());
+        }
+
+        // Pick up where a previous run for this rev left off, unless we've been
+        // asked to start over. Dry runs neither resume nor record progress.
+        if env::var_os("PROMOTE_RELEASE_RESTART").is_some() {
+            println!("discarding any previous progress");
+            state::clear(&self.work, &self.release)?;
+        }
+        let resume = self.load_progress(rev)?;
 
         // If the previously released version is the same rev, then there's
-        // nothing for us to do, nothing has changed.
-        if previous_version.contains(&rev[..7]) {
-            return println!("found rev in previous version, skipping");
+        // nothing for us to do, nothing has changed. When resuming, though, we
+        // may have published the release and died before finishing up, and
+        // then what's live is this release so none of the checks against it
+        // apply. Until then they do, resuming or not.
+        let published = resume.is_some_and(|done| done >= Phase::ReleasePublished);
+        let same_rev = previous_version.as_ref().is_some_and(|v| v.built_from(rev));
+        if !published && same_rev {
+            self.abandon_release()?;
+            return Ok(skip("found rev in previous version"))
         }
 
         // We may still not do a release if the version number hasn't changed.
@@ -136,40 +516,163 @@ impl Context {
         // different and the versions are the same then there's nothing for us
         // to do. This represents a scenario where changes have been merged to
         // the stable/beta branch but the version bump hasn't happened yet.
-        self.download_artifacts(&rev);
-        if self.current_version_same(&previous_version) {
-            return println!("version hasn't changed, skipping");
+        //
+        // Before downloading anything, make sure that CI has uploaded
+        // everything we need so a rev which isn't ready is rejected quickly.
+        let checked = resume.is_none() && self.preflight(rev)?;
+        self.phase(rev, resume, Phase::Downloaded, |cx| cx.download_artifacts(rev))?;
+        let unchanged = match self.current_version_same(previous_version.as_ref()) {
+            Ok(unchanged) => unchanged,
+            Err(e) => {
+                // Don't resume past the checks next time, they have to pass
+                // first.
+                self.abandon_release()?;
+                return Err(e)
+            }
+        };
+        if !published && unchanged {
+            self.abandon_release()?;
+            return Ok(skip("version hasn't changed"))
         }
+        self.report.new_version = self.current_version.as_ref().map(|v| v.to_string());
 
-        self.assert_all_components_present();
+        if !checked {
+            self.assert_all_components_present()?;
+        }
 
         // Ok we've now determined that a release needs to be done. Let's
         // configure rust, build a manifest and sign the artifacts we just downloaded, and upload the
         // signatures and manifest to the CI bucket.
-        self.configure_rust(rev);
-        self.sign_artifacts();
-        self.upload_signatures(&rev);
+        //
+        // The checkout is shared by every channel, so it only stays at our rev
+        // while we hold its lock, from configuring it until x.py is done
+        // signing. Another channel may have checked out its own rev since a
+        // previous run configured ours, so that's always done again.
+        let signed = resume.is_some_and(|done| done >= Phase::Signed);
+        let repo_lock = if self.use_xpy()? && !signed {
+            let lock = self.wait_for_lock("rust")?;
+            self.phase(rev, None, Phase::Configured, |cx| {
+                cx.update_repo()?;
+                cx.configure_rust(rev)
+            })?;
+            Some(lock)
+        } else {
+            None
+        };
+        self.phase(rev, resume, Phase::Signed, |cx| {
+            cx.sign_artifacts(rev)?;
+            cx.verify_signatures(&cx.dist_dir())
+        })?;
+        drop(repo_lock);
+        self.phase(rev, resume, Phase::SignaturesUploaded, |cx| cx.upload_signatures(rev))?;
 
         // Merge all the signatures with the download files, and then sync that
         // whole dir up to the release archives
-        for file in t!(self.build_dir().join("build/dist/").read_dir()) {
+        for file in t!(self.dist_dir().read_dir()) {
             let file = t!(file);
-            t!(fs::copy(file.path(), self.dl_dir().join(file.file_name())));
+            // Downloaded files are linked to the cache, so replace them
+            // rather than writing over them.
+            let dst = self.dl_dir().join(file.file_name());
+            remove_file(&dst)?;
+            t!(fs::copy(file.path(), &dst));
         }
-        self.publish_archive();
-        self.publish_docs();
-        self.publish_release();
+        self.phase(rev, resume, Phase::ArchivePublished, |cx| {
+            cx.diff_release(previous.as_ref())?;
+            cx.publish_archive()
+        })?;
+        self.phase(rev, resume, Phase::DocsPublished, |cx| cx.publish_docs())?;
+        self.phase(rev, resume, Phase::ReleasePublished, |cx| cx.publish_release())?;
+        self.phase(rev, resume, Phase::Indexed, |cx| {
+            let date = cx.date.clone();
+            cx.publish_history(Some(rev), &date)
+        })?;
 
-        self.invalidate_cloudfront();
+        self.phase(rev, resume, Phase::Invalidated, |cx| cx.invalidate_cloudfront())?;
+        self.phase(rev, resume, Phase::Verified, |cx| cx.verify_published())?;
 
         // Clean up after ourselves to avoid leaving gigabytes of artifacts
         // around.
-        drop(fs::remove_dir_all(&self.dl_dir()));
+        self.report.artifacts(&self.dl_dir(), &self.hashes)?;
+        for dir in [self.dl_dir(), self.build_dir(), self.diff_dir()].iter() {
+            self.clean(dir)?;
+        }
+        self.forget_progress()?;
+        self.claim = None;
+        if !self.dry_run && !self.cache().remove(rev)? {
+            println!("leaving {} in the download cache for another release", rev);
+        }
+        Ok(Outcome::Released)
+    }
+
+    /// Discards the recorded progress of this channel's release, so that the
+    /// next run starts over. Dry runs never recorded any.
+    fn forget_progress(&self) -> Result<()> {
+        if self.dry_run {
+            return Ok(())
+        }
+        state::clear(&self.work, &self.release)
+    }
+
+    /// Gives up on releasing, removing the downloaded artifacts along with
+    /// the progress which says they're there.
+    fn abandon_release(&mut self) -> Result<()> {
+        self.forget_progress()?;
+        let dl = self.dl_dir();
+        self.clean(&dl)
+    }
+
+    /// Returns the last phase a previous run completed of the release of
+    /// `rev`, and goes back to the date that release was started on.
+    fn load_progress(&mut self, rev: &str) -> Result<Option<Phase>> {
+        if self.dry_run {
+            return Ok(None)
+        }
+        let progress = match state::load(&self.work, &self.release, rev) {
+            Some(progress) => progress,
+            None => return Ok(None),
+        };
+        // Everything after downloading works on what was downloaded, so if
+        // that's gone there's nothing to resume with.
+        if !self.dl_dir().is_dir() {
+            println!("downloaded artifacts are gone, starting over");
+            self.forget_progress()?;
+            return Ok(None)
+        }
+        println!("resuming release of {} started on {} after phase {}",
+                 rev, progress.date, progress.phase.name());
+        // The manifest signed back then points into the archive of that day,
+        // so a release resumed after midnight is still archived there.
+        self.date = progress.date;
+        Ok(Some(progress.phase))
     }
 
-    fn configure_rust(&mut self, rev: &str) {
+    /// Runs `f` to perform `phase` of the release of `rev` and records that it
+    /// completed, unless `resume` says a previous run already got past it.
+    fn phase<F>(&mut self, rev: &str, resume: Option<Phase>, phase: Phase, f: F) -> Result<()>
+        where F: FnOnce(&mut Context) -> Result<()>
+    {
+        if resume.is_some_and(|done| done >= phase) {
+            println!("already {}, skipping", phase.name());
+            return Ok(())
+        }
+        let start = Instant::now();
+        f(self)?;
+        self.report.phase(phase.name(), start);
+        if !self.dry_run {
+            state::save(&self.work, &self.release, rev, &self.date, phase)?;
+        }
+        #[cfg(test)]
+        {
+            if self.stop_after == Some(phase) {
+                return Err(Error::Network(format!("stopped after {}", phase.name())))
+            }
+        }
+        Ok(())
+    }
+
+    fn configure_rust(&mut self, rev: &str) -> Result<()> {
         let build = self.build_dir();
-        drop(fs::remove_dir_all(&build));
+        self.clean(&build)?;
         t!(fs::create_dir_all(&build));
         let rust = self.rust_dir();
 
@@ -177,11 +680,11 @@ impl Context {
                     .arg("reset")
                     .arg("--hard")
                     .arg(rev)
-                    .current_dir(&rust));
+                    .current_dir(&rust))?;
 
         run(Command::new(rust.join("configure"))
                     .current_dir(&build)
-                    .arg(format!("--release-channel={}", self.release)));
+                    .arg(format!("--release-channel={}", self.release)))?;
         let mut config = String::new();
         let path = build.join("config.toml");
         drop(File::open(&path).and_then(|mut f| f.read_to_string(&mut config)));
@@ -198,51 +701,44 @@ gpg-password-file = \"{}\"
 upload-addr = \"{}/{}\"
 ",
             self.dl_dir().display(),
-            self.secrets["dist"]["gpg-password-file"].as_str().unwrap(),
-            self.secrets["dist"]["upload-addr"].as_str().unwrap(),
-            self.secrets["dist"]["upload-dir"].as_str().unwrap()));
+            self.dist("gpg-password-file")?,
+            self.dist("upload-addr")?,
+            self.dist("upload-dir")?));
         t!(t!(File::create(&path)).write_all(new_config.as_bytes()));
+        Ok(())
     }
 
-    fn current_version_same(&mut self, prev: &str) -> bool {
-        // nightly's always changing
-        if self.release == "nightly" {
-            return false
-        }
-        let prev_version = prev.split(' ').next().unwrap();
-
-        let current = t!(self.dl_dir().read_dir()).filter_map(|e| {
-            let e = t!(e);
-            let filename = e.file_name().into_string().unwrap();
+    fn current_version_same(&mut self, prev: Option<&Version>) -> Result<bool> {
+        let mut current = None;
+        for e in t!(self.dl_dir().read_dir()) {
+            let path = t!(e).path();
+            let filename = file_name(&path)?;
             if !filename.starts_with("rustc-") || !filename.ends_with(".tar.gz") {
-                return None
+                continue
             }
             println!("looking inside {} for a version", filename);
-
-            let file = t!(File::open(&e.path()));
-            let reader = flate2::read::GzDecoder::new(file);
-            let mut archive = tar::Archive::new(reader);
-
-            let entry = t!(archive.entries()).map(|e| t!(e)).filter(|e| {
-                let path = t!(e.path());
-                match path.iter().skip(1).next() {
-                    Some(path) => path == Path::new("version"),
-                    None => false,
+            match package_version(&path) {
+                Ok(version) => {
+                    current = Some(version);
+                    break
                 }
-            }).next();
-            let mut entry = match entry {
-                Some(e) => e,
-                None => return None,
-            };
-            let mut contents = String::new();
-            t!(entry.read_to_string(&mut contents));
-            Some(contents)
-        }).next().expect("no archives with a version");
+                Err(Error::Manifest(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let current = current.ok_or_else(|| {
+            Error::MissingComponents(vec!["rustc tarball with a version".to_string()])
+        })?;
 
         println!("current version: {}", current);
+        let current = Version::parse(&current)?;
+        self.current_version = Some(current.clone());
 
-        let current_version = current.split(' ').next().unwrap();
-        self.current_version = Some(current_version.to_string());
+        // nightly's always changing, and dev releases whatever it's told to
+        let prev = match prev {
+            Some(prev) if self.release != "nightly" && self.release != "dev" => prev,
+            _ => return Ok(false),
+        };
 
         // The release process for beta looks like so:
         //
@@ -251,59 +747,152 @@ upload-addr = \"{}/{}\"
         //
         // In the window between these two steps we don't actually have release
         // artifacts but this script may be run. Try to detect that case here if
-        // the versions mismatch and panic. We'll try again later once that PR
-        // has merged and everything should look good.
-        if (current.contains("nightly") && !prev.contains("nightly")) ||
-           (current.contains("beta") && !prev.contains("beta")) {
-            panic!("looks like channels are being switched -- was this branch \
-                    just created and has a pending PR to change the release \
-                    channel?");
+        // the versions mismatch and bail out. We'll try again later once that
+        // PR has merged and everything should look good.
+        let is_beta = |v: &Version| matches!(v.pre, Pre::Beta(_));
+        if (current.pre == Pre::Nightly && prev.pre != Pre::Nightly) ||
+           (is_beta(&current) && !is_beta(prev)) {
+            return Err(channels_switching())
         }
 
-        prev_version == current_version
+        // Never replace what's live with something older, which would mean
+        // the branch was rewound or we're looking at the wrong artifacts.
+        if current.cmp_release(prev) == Ordering::Less {
+            return Err(Error::Downgrade(format!("{} is older than the live {}", current, prev)))
+        }
+
+        Ok(current.number() == prev.number())
+    }
+
+    /// Lists what CI uploaded for `rev` without downloading it, failing if
+    /// there's nothing or required components are missing, and estimates how
+    /// much there is to download. Returns whether components were checked,
+    /// which needs the version in the tarball names.
+    fn preflight(&mut self, rev: &str) -> Result<bool> {
+        let objects = self.storage.list(&ci_prefix(rev))?;
+        if objects.is_empty() {
+            return Err(not_ready())
+        }
+        let cache = self.cache();
+        let bytes = objects.iter()
+            .filter(|o| !cache.contains(rev, o))
+            .map(|o| o.size)
+            .sum::<u64>();
+        println!("{} files, {} bytes of them not cached, to download", objects.len(), bytes);
+        self.report.download_bytes = Some(bytes);
+
+        // Tarballs are named after the channel they were built for, or the
+        // version number for stable, which we can learn from the one package
+        // without a target. Dev releases whatever they're given.
+        let version = objects.iter().filter_map(|o| {
+            let rest = o.key.strip_prefix("rust-src-")?;
+            rest.strip_suffix(".tar.xz").or_else(|| rest.strip_suffix(".tar.gz"))
+        }).next().map(|v| v.to_string());
+        let version = match version {
+            Some(version) => version,
+            None => {
+                println!("can't tell the version of {}, checking components later", rev);
+                return Ok(false)
+            }
+        };
+        let built_for_channel = match &self.release[..] {
+            "dev" => true,
+            "stable" => !["nightly", "beta", "dev"].contains(&&version[..]),
+            channel => version == channel,
+        };
+        if !built_for_channel {
+            return Err(channels_switching())
+        }
+        let present = objects.iter()
+            .filter_map(|o| manifest::parse(&o.key, &version))
+            .map(|(pkg, target, _)| (pkg, target))
+            .collect();
+        self.check_components(&present)?;
+        Ok(true)
     }
 
     /// Make sure this release comes with a minimum of components.
     ///
     /// Note that we already don't merge PRs in rust-lang/rust that don't
     /// build cargo, so this cannot realistically fail.
-    fn assert_all_components_present(&self) {
-        if self.release != "nightly" {
-            return
-        }
-        let components = t!(self.dl_dir().read_dir())
-            .map(|e| t!(e))
-            .map(|e| e.file_name().into_string().unwrap())
-            .filter(|s| s.contains("x86_64-unknown-linux-gnu"))
+    fn assert_all_components_present(&mut self) -> Result<()> {
+        let version = self.tarball_version();
+        let mut present = BTreeSet::new();
+        for e in t!(self.dl_dir().read_dir()) {
+            let path = t!(e).path();
+            if let Some((pkg, target, _)) = manifest::parse(file_name(&path)?, &version) {
+                present.insert((pkg, target));
+            }
+        }
+        self.check_components(&present)
+    }
+
+    /// Checks the `(package, target)` pairs `present` in the release against
+    /// the components configured in `[dist.components]`.
+    fn check_components(&mut self, present: &BTreeSet<(String, String)>) -> Result<()> {
+        // List everything that's missing before deciding anything, so one run
+        // tells us about every broken target.
+        let missing = components::missing(&self.secrets, &self.release, present)?;
+        for m in missing.iter() {
+            println!("missing {} for {}{}", m.package, m.target,
+                     if m.required { "" } else { " (not required)" });
+        }
+        let required = missing.iter()
+            .filter(|m| m.required)
+            .map(|m| format!("{} ({})", m.package, m.target))
             .collect::<Vec<_>>();
-        println!("components in this nightly {:?}", components);
-        assert!(components.iter().any(|s| s.starts_with("rustc-")));
-        assert!(components.iter().any(|s| s.starts_with("rust-std-")));
-        assert!(components.iter().any(|s| s.starts_with("cargo-")));
-        // For now, produce nightlies even if these are missing.
-        // assert!(components.iter().any(|s| s.starts_with("rustfmt-")));
-        // assert!(components.iter().any(|s| s.starts_with("rls-")));
-        // assert!(components.iter().any(|s| s.starts_with("clippy-")));
+        self.report.missing = missing;
+        if !required.is_empty() {
+            return Err(Error::MissingComponents(required))
+        }
+        Ok(())
     }
 
-    fn download_artifacts(&mut self, rev: &str) {
+    fn download_artifacts(&mut self, rev: &str) -> Result<()> {
         let dl = self.dl_dir();
-        drop(fs::remove_dir_all(&dl));
+        self.clean(&dl)?;
         t!(fs::create_dir_all(&dl));
 
-        let src = format!("s3://rust-lang-ci2/rustc-builds/{}/", rev);
-        run(self.aws_s3()
-                .arg("cp")
-                .arg("--recursive")
-                .arg("--only-show-errors")
-                .arg(&src)
-                .arg(format!("{}/", dl.display())));
+        // Artifacts go through the cache, so that only what isn't there from
+        // an earlier attempt at this rev is downloaded.
+        let src = ci_prefix(rev);
+        let objects = self.storage.list(&src)?;
+        if objects.is_empty() {
+            return Err(not_ready())
+        }
+        let cache = self.cache();
+        self.claim = Some(cache.claim(rev)?);
+        cache.prune(rev, &objects)?;
+        let missing = objects.iter().filter(|o| !cache.contains(rev, o)).collect::<Vec<_>>();
+        println!("{} of {} artifacts cached", objects.len() - missing.len(), objects.len());
 
-        let mut files = t!(dl.read_dir());
-        if files.next().is_none() {
-            panic!("appears that this rev doesn't have any artifacts, \
-                    is this a stable/beta branch awaiting a PR?");
+        // Linking cached artifacts is free, but every .xz without a .gz is
+        // recompressed below, which comes out bigger.
+        let keys = objects.iter().map(|o| &o.key[..]).collect::<BTreeSet<_>>();
+        let recompressed = objects.iter()
+            .filter(|o| match o.key.strip_suffix(".xz") {
+                Some(stem) => !keys.contains(&format!("{}.gz", stem)[..]),
+                None => false,
+            })
+            .map(|o| o.size * 2)
+            .sum::<u64>();
+        let needed = missing.iter().map(|o| o.size).sum::<u64>() + recompressed;
+        cache.evict(needed)?;
+        self.ensure_space("downloading artifacts", needed)?;
+        let storage = &self.storage;
+        parallel(self.download_parallelism(), missing, |object| {
+            cache.fill(rev, object, |tmp| {
+                let url = format!("{}{}", src, object.key);
+                if !storage.get_object(&url, tmp)? {
+                    return Err(Error::Storage(format!("{} disappeared while downloading", url)))
+                }
+                Ok(())
+            })
+        })?;
+        for object in objects.iter() {
+            cache.link(rev, object, &dl.join(&object.key))?;
         }
+        cache.evict(0)?;
 
         // Delete residue signature/hash files. These may come around for a few
         // reasons:
@@ -320,6 +909,8 @@ upload-addr = \"{}/{}\"
         // and xz tarballs have the same content, we did not deploy the gz files
         // from the CI. But rustup users may still expect to get gz files, so we
         // are recompressing the xz files as gz here.
+        self.hashes.clear();
+        let mut recompress = Vec::new();
         for file in t!(dl.read_dir()) {
             let file = t!(file);
             let path = file.path();
@@ -330,272 +921,1362 @@ upload-addr = \"{}/{}\"
                     t!(fs::remove_file(&path));
                 }
                 // Generate *.gz from *.xz...
-                Some("xz") => {
-                    let gz_path = path.with_extension("gz");
-                    if !gz_path.is_file() {
-                        println!("recompressing {}...", gz_path.display());
-                        let xz = t!(File::open(path));
-                        let mut xz = xz2::read::XzDecoder::new(xz);
-                        let gz = t!(File::create(gz_path));
-                        let mut gz = flate2::write::GzEncoder::new(gz, flate2::Compression::best());
-                        t!(io::copy(&mut xz, &mut gz));
-                    }
+                Some("xz") if !path.with_extension("gz").is_file() => {
+                    recompress.push(path);
                 }
                 _ => {}
             }
         }
+
+        // Recompressing with `Compression::best()` is slow, so spread it over
+        // every core. Both tarballs are hashed on the way through so they
+        // never have to be read again for the manifest.
+        let threads = self.secrets.get("dist")
+            .and_then(|d| d.get("recompress-parallelism"))
+            .and_then(|v| v.as_integer())
+            .map(|n| n as usize)
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let total = recompress.len();
+        let done = Mutex::new(0);
+        let hashes = &self.hashes;
+        parallel(threads, recompress, |xz_path| {
+            let start = Instant::now();
+            let gz_path = xz_path.with_extension("gz");
+            let mut xz = Hashing::new(t!(File::open(&xz_path)));
+            let gz = Hashing::new(t!(File::create(&gz_path)));
+            let mut gz = flate2::write::GzEncoder::new(gz, flate2::Compression::best());
+            {
+                let mut decoder = xz2::read::XzDecoder::new(&mut xz);
+                t!(io::copy(&mut decoder, &mut gz));
+            }
+            // Make sure anything after the end of the xz stream is hashed too.
+            t!(io::copy(&mut xz, &mut io::sink()));
+            let gz = t!(gz.finish());
+            let (xz_len, gz_len) = (xz.len(), gz.len());
+            hashes.insert(&xz_path, xz.finish().1);
+            hashes.insert(&gz_path, gz.finish().1);
+
+            let mut done = done.lock().unwrap();
+            *done += 1;
+            println!("[{}/{}] recompressed {} ({} -> {} bytes) in {:.1}s",
+                     done, total, file_name(&gz_path)?,
+                     xz_len, gz_len, start.elapsed().as_secs_f64());
+            Ok(())
+        })
+    }
+
+    /// Publishes the release archived on `date` as the channel again. The
+    /// archive has everything a release needs, already signed, so this
+    /// only checks it and copies it back into place.
+    fn republish(&mut self, date: &str) -> Result<Outcome> {
+        let src = format!("s3://{}/{}/{}/",
+                          self.dist("upload-bucket")?,
+                          self.dist("upload-dir")?,
+                          date);
+        println!("republishing {} from {}", self.release, src);
+        if self.dry_run {
+            println!("dry run, nothing will be published");
+        }
+
+        // Progress is recorded like any other release, under a name which can't
+        // be confused with a rev.
+        let key = format!("archive-{}", date);
+        if env::var_os("PROMOTE_RELEASE_RESTART").is_some() {
+            state::clear(&self.work, &self.release)?;
+        }
+        let resume = self.load_progress(&key)?;
+
+        // Every channel released that day is archived under the same date, and
+        // everything downloaded here is published, so only this channel's
+        // files may be downloaded lest other channels get rolled back too.
+        // The diff against the release before goes back next to the
+        // manifest, but like when it was made it's kept apart from what's
+        // signed.
+        self.phase(&key, resume, Phase::Downloaded, |cx| {
+            let (dl, diff_dir) = (cx.dl_dir(), cx.diff_dir());
+            cx.clean(&dl)?;
+            cx.clean(&diff_dir)?;
+            let (objects, _) = cx.archived_files(&src)?;
+            let needed = objects.iter().map(|o| o.size).sum();
+            cx.ensure_space("downloading the archive", needed)?;
+            t!(fs::create_dir_all(&dl));
+            cx.hashes.clear();
+            let diff = format!("channel-rust-{}-diff.json", cx.release);
+            if objects.iter().any(|o| o.key == diff) {
+                t!(fs::create_dir_all(&diff_dir));
+            }
+            let storage = &cx.storage;
+            parallel(cx.download_parallelism(), objects.iter().collect(), |object| {
+                let url = format!("{}{}", src, object.key);
+                let dst = if object.key == diff { &diff_dir } else { &dl };
+                if !storage.get_object(&url, &dst.join(&object.key))? {
+                    return Err(Error::Storage(format!("{} disappeared while downloading",
+                                                      url)))
+                }
+                Ok(())
+            })
+        })?;
+
+        let dl = self.dl_dir();
+        let manifest = dl.join(format!("channel-rust-{}.toml", self.release));
+        if !manifest.exists() {
+            return Err(Error::Manifest(format!("no {} manifest archived in {}",
+                                               self.release, src)))
+        }
+        let parsed = read_manifest(&manifest)?;
+        let version = rust_version(&parsed)?;
+        println!("archived version: {}", version);
+        self.report.rev = version.commit.clone();
+        self.report.new_version = Some(version.to_string());
+        self.current_version = Some(version);
+        self.check_archive(&parsed)?;
+        self.verify_signatures(&dl)?;
+
+        self.phase(&key, resume, Phase::DocsPublished, |cx| cx.publish_docs())?;
+        self.phase(&key, resume, Phase::ReleasePublished, |cx| cx.publish_release())?;
+        self.phase(&key, resume, Phase::Indexed, |cx| cx.publish_history(None, date))?;
+        self.phase(&key, resume, Phase::Invalidated, |cx| cx.invalidate_cloudfront())?;
+        self.phase(&key, resume, Phase::Verified, |cx| cx.verify_published())?;
+
+        self.report.artifacts(&dl, &self.hashes)?;
+        self.clean(&dl)?;
+        let diff_dir = self.diff_dir();
+        self.clean(&diff_dir)?;
+        self.forget_progress()?;
+        Ok(Outcome::Released)
+    }
+
+    /// The files archived in `src` which belong to this channel, and the
+    /// names of those which other channels' releases list as well, going by
+    /// the manifests archived with them. See `retention::archived`.
+    fn archived_files(&mut self, src: &str)
+                      -> Result<(Vec<storage::Object>, BTreeSet<String>)> {
+        let objects = self.storage.list(src)?
+            .into_iter()
+            .filter(|o| !o.key.contains('/'))
+            .collect::<Vec<_>>();
+        let names = objects.iter().map(|o| o.key.clone()).collect::<Vec<_>>();
+        let manifest_names = names.iter()
+            .filter(|name| name.starts_with("channel-rust-") && name.ends_with(".toml"))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut manifests = Vec::new();
+        for (name, manifest) in self.read_remote_manifests(src, &manifest_names)? {
+            let artifacts = manifest_artifacts(&manifest).iter()
+                .map(|(url, _)| url_file_name(url).map(|name| name.to_string()))
+                .collect::<Result<Vec<_>>>()?;
+            manifests.push((name, artifacts));
+        }
+        let archived = retention::archived(&self.release, &names, &manifests);
+        let objects = objects.into_iter().filter(|o| archived.files.contains(&o.key)).collect();
+        Ok((objects, archived.shared))
+    }
+
+    /// Downloads and parses those of the manifests `names` which exist in
+    /// the remote `prefix`.
+    fn read_remote_manifests(&mut self, prefix: &str, names: &[String])
+                             -> Result<Vec<(String, toml::Value)>> {
+        let dir = self.channel_dir().join("manifests");
+        self.clean(&dir)?;
+        t!(fs::create_dir_all(&dir));
+        let mut manifests = Vec::new();
+        for name in names {
+            let local = dir.join(name);
+            if self.storage.get_object(&format!("{}{}", prefix, name), &local)? {
+                manifests.push((name.clone(), read_manifest(&local)?));
+            }
+        }
+        self.clean(&dir)?;
+        Ok(manifests)
+    }
+
+    /// Prints every date archived for this channel, most recent first, as
+    /// candidates to roll back to.
+    fn list_archives(&mut self) -> Result<()> {
+        let prefix = format!("s3://{}/{}/",
+                             self.dist("upload-bucket")?,
+                             self.dist("upload-dir")?);
+        let dates = self.archives(&prefix)?;
+        if dates.is_empty() {
+            println!("no releases archived in {}", prefix);
+        }
+        for date in dates.iter().rev() {
+            println!("{}", date);
+        }
+        Ok(())
+    }
+
+    /// The dates, in order, under which a release of this channel is
+    /// archived in the remote `prefix`, i.e. which have its manifest. Other
+    /// channels' releases are archived in the same place.
+    fn archives(&self, prefix: &str) -> Result<Vec<String>> {
+        let dates = self.storage.list_dirs(prefix)?
+            .into_iter()
+            .filter(|dir| is_date(dir))
+            .collect::<Vec<_>>();
+        let manifest = format!("channel-rust-{}.toml", self.release);
+        let archived = Mutex::new(Vec::new());
+        let storage = &self.storage;
+        parallel(self.download_parallelism(), dates, |date| {
+            let objects = storage.list(&format!("{}{}/{}", prefix, date, manifest))?;
+            if objects.iter().any(|o| o.key.is_empty()) {
+                archived.lock().unwrap().push(date);
+            }
+            Ok(())
+        })?;
+        let mut archived = archived.into_inner().unwrap();
+        archived.sort();
+        Ok(archived)
+    }
+
+    /// Deletes this channel's files from every archived release which the
+    /// retention policy doesn't keep, and which none of the channel's live
+    /// manifests still point into.
+    fn gc(&mut self) -> Result<Outcome> {
+        let policy = retention::Policy::from_secrets(&self.secrets, &self.release)?;
+        let prefix = format!("s3://{}/{}/",
+                             self.dist("upload-bucket")?,
+                             self.dist("upload-dir")?);
+        let mut dates = self.storage.list_dirs(&prefix)?
+            .into_iter()
+            .filter(|dir| is_date(dir))
+            .collect::<Vec<_>>();
+        dates.sort();
+        let live = self.live_dates(&prefix)?;
+
+        // Several channels are archived under the same date, so only this
+        // channel's files are deleted from each, and only those which no
+        // other channel's release lists too.
+        let mut doomed = Vec::new();
+        let mut bytes = 0;
+        for date in dates.iter() {
+            if let Some(reason) = policy.keep(date, &self.date)? {
+                println!("keeping {}: {}", date, reason);
+                continue
+            }
+            // Artifacts are served out of the archive, so whatever is live
+            // has to stay there however old it is.
+            if live.contains(date) {
+                println!("keeping {}: referenced by a live manifest", date);
+                continue
+            }
+            let (objects, shared) = self.archived_files(&format!("{}{}/", prefix, date))?;
+            for name in shared.iter() {
+                println!("keeping {}/{}: listed by another channel too", date, name);
+            }
+            let objects = objects.into_iter()
+                .filter(|o| !shared.contains(&o.key))
+                .collect::<Vec<_>>();
+            if objects.is_empty() {
+                continue
+            }
+            let size = objects.iter().map(|o| o.size).sum::<u64>();
+            println!("deleting {}: {} files, {} bytes", date, objects.len(), size);
+            bytes += size;
+            doomed.extend(objects.into_iter().map(|o| format!("{}/{}", date, o.key)));
+        }
+        println!("{} files, {} bytes to delete from {}", doomed.len(), bytes, prefix);
+
+        if self.dry_run {
+            for key in doomed.iter() {
+                println!("would delete {}{}", prefix, key);
+            }
+            return Ok(Outcome::Collected { files: 0, bytes: 0 })
+        }
+        let files = doomed.len();
+        self.storage.delete(&prefix, doomed)?;
+        Ok(Outcome::Collected { files, bytes })
+    }
+
+    /// Dates of the archived releases which the artifact urls of this
+    /// channel's live manifests, in the remote `prefix`, point into. For
+    /// stable that includes the manifests of every version.
+    fn live_dates(&mut self, prefix: &str) -> Result<BTreeSet<String>> {
+        let names = self.storage.list(&format!("{}channel-rust-", prefix))?
+            .into_iter()
+            .map(|object| format!("channel-rust-{}", object.key))
+            .filter(|name| !name.contains('/') && name.ends_with(".toml"))
+            .filter(|name| retention::manifest_channel(name) == Some(&self.release[..]))
+            .collect::<Vec<_>>();
+        let mut dates = BTreeSet::new();
+        for (_, manifest) in self.read_remote_manifests(prefix, &names)? {
+            for (url, _) in manifest_artifacts(&manifest) {
+                let mut parts = url.rsplit('/').skip(1);
+                if let Some(date) = parts.next().filter(|d| is_date(d)) {
+                    dates.insert(date.to_string());
+                }
+            }
+        }
+        Ok(dates)
+    }
+
+    /// Adds this release, of `rev` or whatever its manifest says, to the
+    /// channel's history and publishes it next to the channel manifest. It's
+    /// recorded as released today from the archive of `archived`, so that
+    /// rolling back goes down in history as a release of its own.
+    fn publish_history(&mut self, rev: Option<&str>, archived: &str) -> Result<()> {
+        let name = history::file_name(&self.release);
+        let dst = format!("s3://{}/{}/{}",
+                          self.dist("upload-bucket")?,
+                          self.dist("upload-dir")?,
+                          name);
+        let local = self.work.join(&name);
+        remove_file(&local)?;
+        self.storage.get_object(&dst, &local)?;
+        let mut history = History::load(&local, &self.release)?;
+
+        let manifest = self.dl_dir().join(format!("channel-rust-{}.toml", self.release));
+        let mut entry = self.history_entry(archived, rev, &manifest)?;
+        entry.date = self.date.clone();
+        history.add(entry);
+        history.write(&local)?;
+        if self.dry_run {
+            println!("would upload {} to {}", local.display(), dst);
+            return Ok(())
+        }
+        self.storage.put_object(&local, &dst)
+    }
+
+    /// Rebuilds the channel's history out of the manifests in the archive.
+    fn rebuild_history(&mut self) -> Result<Outcome> {
+        let prefix = format!("s3://{}/{}/",
+                             self.dist("upload-bucket")?,
+                             self.dist("upload-dir")?);
+        let mut dates = self.storage.list_dirs(&prefix)?
+            .into_iter()
+            .filter(|dir| is_date(dir))
+            .collect::<Vec<_>>();
+        dates.sort();
+
+        let tmp = self.channel_dir().join("history");
+        self.clean(&tmp)?;
+        t!(fs::create_dir_all(&tmp));
+        let mut history = History::new(&self.release);
+        for date in dates.iter() {
+            let name = format!("channel-rust-{}.toml", self.release);
+            let manifest = tmp.join(&name);
+            if !self.storage.get_object(&format!("{}{}/{}", prefix, date, name), &manifest)? {
+                continue
+            }
+            let name = format!("channel-rust-{}-git-commit-hash.txt", self.release);
+            let hash = tmp.join(&name);
+            remove_file(&hash)?;
+            let rev = if self.storage.get_object(&format!("{}{}/{}", prefix, date, name), &hash)? {
+                let mut rev = String::new();
+                t!(t!(File::open(&hash)).read_to_string(&mut rev));
+                Some(rev.trim().to_string())
+            } else {
+                None
+            };
+            history.add(self.history_entry(date, rev.as_deref(), &manifest)?);
+            self.hashes.clear();
+        }
+
+        let name = history::file_name(&self.release);
+        let local = self.work.join(&name);
+        history.write(&local)?;
+        self.clean(&tmp)?;
+        println!("found {} {} releases in {}", history.len(), self.release, prefix);
+        let dst = format!("{}{}", prefix, name);
+        if self.dry_run {
+            println!("would upload {} to {}", local.display(), dst);
+        } else {
+            self.storage.put_object(&local, &dst)?;
+        }
+        Ok(Outcome::Indexed(history.len()))
+    }
+
+    /// Describes the release archived on `date` with the channel manifest
+    /// `manifest`. Without the full `rev` the manifest's abbreviated one is
+    /// recorded.
+    fn history_entry(&self, date: &str, rev: Option<&str>, manifest: &Path)
+        -> Result<history::Entry>
+    {
+        let version = rust_version(&read_manifest(manifest)?)?;
+        Ok(history::Entry {
+            date: date.to_string(),
+            channel: self.release.clone(),
+            rev: rev.map(|r| r.to_string()).or_else(|| version.commit.clone()).unwrap_or_default(),
+            version: version.to_string(),
+            manifest_url: format!("{}/{}/{}/{}",
+                                  self.dist("upload-addr")?,
+                                  self.dist("upload-dir")?,
+                                  date,
+                                  file_name(manifest)?),
+            manifest_sha256: self.hashes.sha256(manifest)?,
+        })
+    }
+
+    /// Checks that every artifact listed in an archived `manifest` was
+    /// archived along with it, unchanged and signed.
+    fn check_archive(&mut self, manifest: &toml::Value) -> Result<()> {
+        let dl = self.dl_dir();
+        let artifacts = manifest_artifacts(manifest);
+        for (url, hash) in &artifacts {
+            let name = url_file_name(url)?;
+            let file = dl.join(name);
+            if !file.exists() {
+                return Err(Error::Manifest(format!("{} is missing from the archive", name)))
+            }
+            if self.hashes.sha256(&file)? != *hash {
+                return Err(Error::Manifest(format!("archived {} doesn't match the manifest",
+                                                   name)))
+            }
+            if !dl.join(format!("{}.asc", name)).exists() {
+                return Err(Error::Manifest(format!("{} was archived without a signature",
+                                                   name)))
+            }
+        }
+        println!("all {} artifacts in the archived manifest are present", artifacts.len());
+        Ok(())
     }
 
     /// Create manifest and sign the artifacts.
-    fn sign_artifacts(&mut self) {
-        let build = self.build_dir();
-        // This calls `src/tools/build-manifest` from the rustc repo.
-        run(Command::new(self.rust_dir().join("x.py"))
-                    .current_dir(&build)
-                    .arg("dist")
-                    .arg("hash-and-sign"));
+    fn sign_artifacts(&mut self, rev: &str) -> Result<()> {
+        if self.use_xpy()? {
+            let build = self.build_dir();
+            // This calls `src/tools/build-manifest` from the rustc repo.
+            return run(Command::new(self.rust_dir().join("x.py"))
+                               .current_dir(&build)
+                               .arg("dist")
+                               .arg("hash-and-sign"))
+        }
+
+        let dist = self.dist_dir();
+        self.clean(&dist)?;
+        t!(fs::create_dir_all(&dist));
+        let version = self.tarball_version();
+        let url = format!("{}/{}", self.dist("upload-addr")?, self.dist("upload-dir")?);
+        let manifests = manifest::Builder {
+            dl: &self.dl_dir(),
+            out: &dist,
+            channel: &self.release,
+            version: &version,
+            date: &self.date,
+            rev,
+            url: &url,
+            hashes: &self.hashes,
+        }.build()?;
+
+        let key = pgp::SecretKey::load(&self.channel_dir().join("gnupg-sign"),
+                                       Path::new(self.dist("gpg-key")?),
+                                       Path::new(self.dist("gpg-password-file")?))?;
+        println!("signing with key {}", key.public().fingerprint());
+
+        let mut files = files_in(&self.dl_dir())?;
+        files.extend(manifests.iter().map(|m| dist.join(m)));
+        for file in files {
+            let asc = dist.join(format!("{}.asc", file_name(&file)?));
+            let signature = key.sign(&file)?;
+            t!(t!(File::create(&asc)).write_all(signature.as_bytes()));
+        }
+        Ok(())
+    }
+
+    /// Checks every signature in `dist` against the public half of
+    /// `dist.gpg-key`, or `dist.gpg-public-key` if that's set.
+    fn verify_signatures(&mut self, dist: &Path) -> Result<()> {
+        let key = self.public_key()?;
+
+        let mut verified = 0;
+        for asc in files_in(dist)? {
+            let name = file_name(&asc)?;
+            let signed = match name.strip_suffix(".asc") {
+                Some(signed) => signed,
+                None => continue,
+            };
+            // Manifests are signed where they're generated, everything else
+            // is signed in the download directory.
+            let file = if dist.join(signed).exists() {
+                dist.join(signed)
+            } else {
+                self.dl_dir().join(signed)
+            };
+            let mut signature = String::new();
+            t!(t!(File::open(&asc)).read_to_string(&mut signature));
+            key.verify(&file, &signature)?;
+            verified += 1;
+        }
+        println!("verified {} signatures by {}", verified, key.fingerprint());
+        Ok(())
+    }
+
+    fn upload_signatures(&mut self, rev: &str) -> Result<()> {
+        let dst = ci_prefix(rev);
+        if self.dry_run {
+            return plan_copy(&self.dist_dir(), &dst);
+        }
+        let src = format!("{}/", self.dist_dir().display());
+        self.storage.copy_recursive(&src, &dst, None)
     }
 
-    fn upload_signatures(&mut self, rev: &str) {
-        let dst = format!("s3://rust-lang-ci2/rustc-builds/{}/", rev);
-        run(self.aws_s3()
-                .arg("cp")
-                .arg("--recursive")
-                .arg("--only-show-errors")
-                .arg(self.build_dir().join("build/dist/"))
-                .arg(&dst));
+    /// Compares the new manifest with the `previous` live one, printing the
+    /// differences and saving them as `channel-rust-$channel-diff.json` in
+    /// `diff_dir`, to be published next to the manifest. That's only done
+    /// after signing, so it's kept out of the download directory where
+    /// everything is signed and hashed.
+    fn diff_release(&mut self, previous: Option<&toml::Value>) -> Result<()> {
+        let diff_dir = self.diff_dir();
+        self.clean(&diff_dir)?;
+        let previous = match previous {
+            Some(previous) => previous,
+            None => {
+                println!("no previous release to compare against");
+                return Ok(())
+            }
+        };
+        let dl = self.dl_dir();
+        let current = read_manifest(&dl.join(format!("channel-rust-{}.toml", self.release)))?;
+
+        // Manifests don't record sizes, so the previous release's come from
+        // its copy in the archive.
+        let mut previous_sizes = HashMap::new();
+        if let Some(date) = previous.get("date").and_then(|d| d.as_str()) {
+            let archive = format!("s3://{}/{}/{}/",
+                                  self.dist("upload-bucket")?,
+                                  self.dist("upload-dir")?,
+                                  date);
+            for object in self.storage.list(&archive)? {
+                previous_sizes.insert(object.key, object.size);
+            }
+        }
+        let mut current_sizes = HashMap::new();
+        for file in files_in(&dl)? {
+            let name = file_name(&file)?.to_string();
+            current_sizes.insert(name, t!(file.metadata()).len());
+        }
+
+        let threshold = self.secrets.get("dist")
+            .and_then(|d| d.get("diff-threshold"))
+            .and_then(|t| t.as_float().or_else(|| t.as_integer().map(|i| i as f64)))
+            .unwrap_or(10.0);
+        let diff = diff::Diff::new(previous, &current, &previous_sizes, &current_sizes,
+                                   threshold);
+        diff.print();
+        t!(fs::create_dir_all(&diff_dir));
+        diff.write(&diff_dir.join(format!("channel-rust-{}-diff.json", self.release)))?;
+        self.report.diff = Some(diff.to_json());
+        Ok(())
     }
 
-    fn publish_archive(&mut self) {
-        let bucket = self.secrets["dist"]["upload-bucket"].as_str().unwrap();
-        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+    fn publish_archive(&mut self) -> Result<()> {
+        let bucket = self.dist("upload-bucket")?;
+        let dir = self.dist("upload-dir")?;
         let dst = format!("s3://{}/{}/{}/", bucket, dir, self.date);
-        run(self.aws_s3()
-                .arg("cp")
-                .arg("--recursive")
-                .arg("--only-show-errors")
-                .arg("--metadata-directive")
-                .arg("REPLACE")
-                .arg("--cache-control")
-                .arg("public")
-                .arg(format!("{}/", self.dl_dir().display()))
-                .arg(&dst));
-    }
-
-    fn publish_docs(&mut self) {
-        let (version, upload_dir) = match &self.release[..] {
-            "stable" => {
-                let vers = &self.current_version.as_ref().unwrap()[..];
-                (vers, "stable")
-            }
-            "beta" => ("beta", "beta"),
-            "nightly" => ("nightly", "nightly"),
-            _ => panic!(),
+        for src in self.release_dirs() {
+            if self.dry_run {
+                plan_copy(&src, &dst)?;
+            } else {
+                let src = format!("{}/", src.display());
+                self.storage.copy_recursive(&src, &dst, Some("public"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The directories whose contents are published as the release: the
+    /// download directory, and `diff_dir` if there's a diff.
+    fn release_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![self.dl_dir()];
+        if self.diff_dir().exists() {
+            dirs.push(self.diff_dir());
+        }
+        dirs
+    }
+
+    fn diff_dir(&self) -> PathBuf {
+        self.channel_dir().join("diff")
+    }
+
+    fn publish_docs(&mut self) -> Result<()> {
+        let version = self.tarball_version();
+        let upload_dir = match &self.release[..] {
+            "stable" => "stable",
+            "beta" => "beta",
+            "nightly" => "nightly",
+            // There's no separate docs site for dev releases, and they mustn't
+            // replace any of the real docs.
+            "dev" => {
+                println!("not publishing docs for dev releases");
+                return Ok(())
+            }
+            _ => return Err(Error::Config(format!("unknown release: {}", self.release))),
         };
 
-        // Pull out HTML documentation from one of the `rust-docs-*` tarballs.
-        // For now we just arbitrarily pick x86_64-unknown-linux-gnu.
-        let docs = self.work.join("docs");
-        drop(fs::remove_dir_all(&docs));
+        // Upload to `/doc/$channel`, and stable docs also go to
+        // `/doc/$version`.
+        let mut dirs = vec![upload_dir.to_string()];
+        if upload_dir == "stable" {
+            dirs.push(version);
+        }
+        self.publish_docs_to(&dirs)
+    }
+
+    /// Extracts the HTML documentation from the downloaded `rust-docs` and
+    /// `rustc-docs` tarballs and publishes it to each of `/doc/$dir`.
+    fn publish_docs_to(&mut self, dirs: &[String]) -> Result<()> {
+        let version = self.tarball_version();
+        let docs = self.channel_dir().join("docs");
+        self.clean(&docs)?;
+
+        let tarball = match self.docs_tarball("rust-docs", &version)? {
+            Some(tarball) => tarball,
+            None => {
+                return Err(Error::Manifest(format!("no rust-docs-{} tarball to publish \
+                                                    docs from", version)))
+            }
+        };
+        let html = docs_html(&tarball, "rust-docs")?;
+        let rustc_tarball = self.docs_tarball("rustc-docs", &version)?;
+        let mut needed = docs::unpacked_size(&tarball, &html)?;
+        if let Some(ref tarball) = rustc_tarball {
+            needed += docs::unpacked_size(tarball, &docs_html(tarball, "rustc-docs")?)?;
+        }
+        self.ensure_space("unpacking docs", needed)?;
         t!(fs::create_dir_all(&docs));
-        let target = "x86_64-unknown-linux-gnu";
 
         // Unpack the regular documentation tarball.
-        let tarball_prefix = format!("rust-docs-{}-{}", version, target);
-        let tarball = format!("{}.tar.gz", self.dl_dir().join(&tarball_prefix).display());
-        let tarball_dir = format!("{}/rust-docs/share/doc/rust/html", tarball_prefix);
-        run(Command::new("tar")
-                    .arg("xf")
-                    .arg(&tarball)
-                    .arg("--strip-components=6")
-                    .arg(&tarball_dir)
-                    .current_dir(&docs));
-
-        // Construct path to rustc documentation.
-        let tarball_prefix = format!("rustc-docs-{}-{}", version, target);
-        let tarball = format!("{}.tar.gz", self.dl_dir().join(&tarball_prefix).display());
-
-        // Only create and unpack rustc docs if artefacts include tarball.
-        if Path::new(&tarball).exists() {
+        if !docs::unpack(&tarball, &html, &docs)? {
+            return Err(Error::Manifest(format!("no docs in {}", tarball.display())))
+        }
+
+        // Only unpack rustc docs if the artifacts include them, which newer
+        // tarballs keep in a `rustc` subdirectory.
+        if let Some(tarball) = rustc_tarball {
             let rustc_docs = docs.join("nightly-rustc");
             t!(fs::create_dir_all(&rustc_docs));
+            let html = docs_html(&tarball, "rustc-docs")?;
+            if !docs::unpack(&tarball, &format!("{}/rustc", html), &rustc_docs)? {
+                docs::unpack(&tarball, &html, &rustc_docs)?;
+            }
+        }
 
-            // Construct the path that contains the documentation inside the tarball.
-            let tarball_dir = format!("{}/rustc-docs/share/doc/rust/html", tarball_prefix);
-            let tarball_dir_new = format!("{}/rustc", tarball_dir);
-
-            if t!(Command::new("tar")
-                .arg("tf")
-                .arg(&tarball)
-                .arg(&tarball_dir_new)
-                .current_dir(&rustc_docs)
-                .output())
-                .status
-                .success() {
-                // Unpack the rustc documentation into the new directory.
-                run(Command::new("tar")
-                    .arg("xf")
-                    .arg(&tarball)
-                    .arg("--strip-components=7")
-                    .arg(&tarball_dir_new)
-                    .current_dir(&rustc_docs));
-            } else {
-                // Unpack the rustc documentation into the new directory.
-                run(Command::new("tar")
-                    .arg("xf")
-                    .arg(&tarball)
-                    .arg("--strip-components=6")
-                    .arg(&tarball_dir)
-                    .current_dir(&rustc_docs));
+        let bucket = self.dist("upload-bucket")?.to_string();
+        for dir in dirs {
+            let dst = format!("s3://{}/doc/{}/", bucket, dir);
+            let changed = self.sync_docs(&docs, &dst)?;
+            self.invalidate_docs(dir, &changed)?;
+        }
+        self.clean(&docs)
+    }
+
+    /// Finds the downloaded `$package-$version-$target.tar.gz` to take docs
+    /// from, preferring the targets in `dist.docs-targets` and falling back
+    /// to any other target that has them.
+    fn docs_tarball(&self, package: &str, version: &str) -> Result<Option<PathBuf>> {
+        let prefix = format!("{}-{}-", package, version);
+        let mut available = Vec::new();
+        for file in files_in(&self.dl_dir())? {
+            let name = file_name(&file)?;
+            let target = name.strip_prefix(&prefix[..]).and_then(|t| t.strip_suffix(".tar.gz"));
+            if let Some(target) = target {
+                available.push(target.to_string());
+            }
+        }
+        let target = match docs::pick_target(&self.docs_targets()?, &available) {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        println!("publishing {} from {}", package, target);
+        Ok(Some(self.dl_dir().join(format!("{}{}.tar.gz", prefix, target))))
+    }
+
+    /// The targets whose docs are published when they're available, in order
+    /// of preference.
+    fn docs_targets(&self) -> Result<Vec<String>> {
+        match self.secrets.get("dist").and_then(|d| d.get("docs-targets")) {
+            Some(targets) => {
+                targets.as_array()
+                    .and_then(|a| a.iter().map(|t| t.as_str().map(|s| s.to_string())).collect())
+                    .ok_or_else(|| {
+                        Error::Config("`dist.docs-targets` must be a list of strings".to_string())
+                    })
             }
+            None => Ok(vec!["x86_64-unknown-linux-gnu".to_string()]),
+        }
+    }
 
+    /// Publishes the docs of the channel's live release, or of the stable
+    /// version given on the command line, without releasing anything.
+    fn republish_docs(&mut self) -> Result<Outcome> {
+        let version = match self.docs_version.clone() {
+            Some(version) => Version::parse(&version)?,
+            None => rust_version(&self.download_manifest()?)?,
+        };
+        println!("republishing docs of {} {}", self.release, version);
+        self.report.new_version = Some(version.to_string());
+        self.current_version = Some(version);
+
+        // Only the docs tarballs are needed, which live next to the channel
+        // manifest for as long as that's current and forever for stable.
+        let dl = self.dl_dir();
+        self.clean(&dl)?;
+        t!(fs::create_dir_all(&dl));
+        self.hashes.clear();
+        let tarball_version = self.tarball_version();
+        let src = format!("s3://{}/{}/", self.dist("upload-bucket")?, self.dist("upload-dir")?);
+        let mut tarballs = Vec::new();
+        for package in ["rust-docs", "rustc-docs"].iter() {
+            let prefix = format!("{}{}-{}-", src, package, tarball_version);
+            let objects = self.storage.list(&prefix)?;
+            let targets = objects.iter()
+                .filter_map(|o| o.key.strip_suffix(".tar.gz").map(|t| t.to_string()))
+                .collect::<Vec<_>>();
+            if let Some(target) = docs::pick_target(&self.docs_targets()?, &targets) {
+                let size = objects.iter()
+                    .find(|o| o.key == format!("{}.tar.gz", target))
+                    .map_or(0, |o| o.size);
+                let name = format!("{}-{}-{}.tar.gz", package, tarball_version, target);
+                tarballs.push((name, size));
+            }
+        }
+        let needed = tarballs.iter().map(|(_, size)| size).sum();
+        self.ensure_space("downloading docs", needed)?;
+        for (name, _) in tarballs {
+            self.storage.get_object(&format!("{}{}", src, name), &dl.join(&name))?;
         }
 
-        // Upload this to `/doc/$channel`
-        let bucket = self.secrets["dist"]["upload-bucket"].as_str().unwrap();
-        let dst = format!("s3://{}/doc/{}/", bucket, upload_dir);
-        run(self.aws_s3()
-                .arg("sync")
-                .arg("--delete")
-                .arg("--only-show-errors")
-                .arg(format!("{}/", docs.display()))
-                .arg(&dst));
-        self.invalidate_docs(upload_dir);
+        match self.docs_version.clone() {
+            // Older stable versions only replace their own docs.
+            Some(version) => self.publish_docs_to(&[version])?,
+            None => self.publish_docs()?,
+        }
+        self.clean(&dl)?;
+        Ok(Outcome::DocsPublished)
+    }
 
-        // Stable artifacts also go to `/doc/$version/
-        if upload_dir == "stable" {
-            let dst = format!("s3://{}/doc/{}/", bucket, version);
-            run(self.aws_s3()
-                    .arg("sync")
-                    .arg("--delete")
-                    .arg("--only-show-errors")
-                    .arg(format!("{}/", docs.display()))
-                    .arg(&dst));
-            self.invalidate_docs(&version);
-        }
-    }
-
-    fn invalidate_docs(&self, dir: &str) {
-        let distribution_id = self.secrets["dist"]["rustdoc-cf-distribution-id"]
-                                          .as_str().unwrap();
-        let mut cmd = Command::new("aws");
-        self.aws_creds(&mut cmd);
-        cmd.arg("cloudfront")
-            .arg("create-invalidation")
-            .arg("--distribution-id").arg(distribution_id);
-        if dir == "stable" {
-            cmd.arg("--paths").arg("/*");
+    /// Makes `dst` mirror `docs`, returning the keys of everything which was
+    /// changed or deleted.
+    fn sync_docs(&self, docs: &Path, dst: &str) -> Result<Vec<String>> {
+        let remote = self.storage.list(dst)?;
+        let mut changed = self.changed_files(docs, &remote)?;
+        for object in remote {
+            if !docs.join(&object.key).exists() {
+                if self.dry_run {
+                    println!("would delete {}{} ({} bytes)", dst, object.key, object.size);
+                }
+                changed.push(object.key);
+            }
+        }
+        if self.dry_run {
+            plan_copy(docs, dst)?;
         } else {
-            cmd.arg("--paths").arg(format!("/{0}/*", dir));
+            self.storage.sync_delete(docs, dst)?;
         }
-        run(&mut cmd);
+        Ok(changed)
     }
 
-    fn publish_release(&mut self) {
-        let bucket = self.secrets["dist"]["upload-bucket"].as_str().unwrap();
-        let dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+    /// Invalidates the `changed` keys of `/doc/$dir`, which the docs
+    /// distribution serves as `/$dir`, or at the root for stable.
+    fn invalidate_docs(&mut self, dir: &str, changed: &[String]) -> Result<()> {
+        let distribution_id = self.dist("rustdoc-cf-distribution-id")?.to_string();
+        let prefix = if dir == "stable" { String::new() } else { format!("/{}", dir) };
+        let paths = changed.iter().map(|key| format!("{}/{}", prefix, key)).collect();
+        self.invalidate(&distribution_id, paths)
+    }
+
+    fn publish_release(&mut self) -> Result<()> {
+        let bucket = self.dist("upload-bucket")?;
+        let dir = self.dist("upload-dir")?;
         let dst = format!("s3://{}/{}/", bucket, dir);
-        run(self.aws_s3()
-                .arg("cp")
-                .arg("--recursive")
-                .arg("--only-show-errors")
-                .arg(format!("{}/", self.dl_dir().display()))
-                .arg(&dst));
-    }
-
-    fn invalidate_cloudfront(&mut self) {
-        let json = json!({
-            "Paths": {
-                "Items": [
-                    "/dist/channel*",
-                    "/dist/rust*",
-                    "/dist/index*",
-                    "/dist/",
-                ],
-                "Quantity": 4,
-            },
-            "CallerReference": format!("rct-{}", rand::random::<usize>()),
-        }).to_string();
-        let dst = self.work.join("payload.json");
-        t!(t!(File::create(&dst)).write_all(json.as_bytes()));
-
-        let distribution_id = self.secrets["dist"]["cloudfront-distribution-id"]
-                                          .as_str().unwrap();
-        let mut cmd = Command::new("aws");
-        self.aws_creds(&mut cmd);
-        run(cmd.arg("cloudfront")
-               .arg("create-invalidation")
-               .arg("--invalidation-batch").arg(format!("file://{}", dst.display()))
-               .arg("--distribution-id").arg(distribution_id));
+
+        // Remember what's about to change for `invalidate_cloudfront`. Only
+        // the release's own artifacts are listed, as the directory holds the
+        // whole dated archive as well.
+        let version = format!("-{}-", self.tarball_version());
+        let mut changed = Vec::new();
+        for src in self.release_dirs() {
+            let prefixes = files_in(&src)?.iter()
+                .map(|file| {
+                    let key = storage::key_for(file.strip_prefix(&src).unwrap());
+                    match key.find(&version) {
+                        Some(i) => key[..i + version.len()].to_string(),
+                        None => key,
+                    }
+                })
+                .collect::<BTreeSet<_>>();
+            let mut remote = Vec::new();
+            for prefix in prefixes {
+                for mut object in self.storage.list(&format!("{}{}", dst, prefix))? {
+                    object.key = format!("{}{}", prefix, object.key);
+                    remote.push(object);
+                }
+            }
+            changed.extend(self.changed_files(&src, &remote)?);
+        }
+        t!(fs::write(self.changed_path(), changed.join("\n")));
+
+        for src in self.release_dirs() {
+            if self.dry_run {
+                plan_copy(&src, &dst)?;
+            } else {
+                let src = format!("{}/", src.display());
+                self.storage.copy_recursive(&src, &dst, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the keys of the files underneath `dir` which aren't among the
+    /// `remote` objects already, with the same contents. Only storage which
+    /// lists S3's MD5 ETags can tell, so with any other every file changed.
+    fn changed_files(&self, dir: &Path, remote: &[storage::Object]) -> Result<Vec<String>> {
+        let files = files_in(dir)?;
+        let keys = files.iter().map(|file| storage::key_for(file.strip_prefix(dir).unwrap()));
+        if !self.storage.md5_etags() {
+            return Ok(keys.collect())
+        }
+        let remote = remote.iter().map(|o| (&o.key[..], o)).collect::<HashMap<_, _>>();
+        let mut changed = Vec::new();
+        for (file, key) in files.iter().zip(keys) {
+            let same = match remote.get(&key[..]) {
+                Some(object) => {
+                    object.size == t!(file.metadata()).len() &&
+                        object.etag == self.hashes.etag(file)?
+                }
+                None => false,
+            };
+            if !same {
+                changed.push(key);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Where `publish_release` records which of the release's files changed.
+    fn changed_path(&self) -> PathBuf {
+        self.channel_dir().join("changed")
+    }
+
+    /// Invalidates whatever the release changed next to the channel
+    /// manifest.
+    fn invalidate_cloudfront(&mut self) -> Result<()> {
+        let dir = self.dist("upload-dir")?.to_string();
+        let changed = t!(fs::read_to_string(self.changed_path()));
+        let mut paths = changed.lines()
+            .map(|key| format!("/{}/{}", dir, key))
+            .collect::<Vec<_>>();
+        paths.push(format!("/{}/{}", dir, history::file_name(&self.release)));
+        // The directory listings and their index pages mention every file.
+        paths.push(format!("/{}/index*", dir));
+        paths.push(format!("/{}/", dir));
+
+        // Dev releases never invalidate the production distribution, only
+        // their own if they have one.
+        let distribution_id = if self.release == "dev" {
+            match self.dev_dist("cloudfront-distribution-id") {
+                Some(id) => id,
+                None => {
+                    println!("no CloudFront distribution for dev releases, not invalidating");
+                    return Ok(())
+                }
+            }
+        } else {
+            self.dist("cloudfront-distribution-id")?
+        }.to_string();
+        self.invalidate(&distribution_id, paths)
+    }
+
+    /// Invalidates `paths` in the CloudFront distribution `distribution_id`,
+    /// collapsing them into wildcards if there are more than
+    /// `dist.invalidation-max-paths`, and waits for that to complete.
+    fn invalidate(&mut self, distribution_id: &str, paths: Vec<String>) -> Result<()> {
+        let optional = |key| self.secrets.get("dist").and_then(|d| d.get(key));
+        let max = optional("invalidation-max-paths")
+            .and_then(|m| m.as_integer())
+            .unwrap_or(3000);
+        let paths = cloudfront::collapse(paths, max as usize);
+        if self.dry_run {
+            for path in paths.iter() {
+                println!("would invalidate {} in distribution {}", path, distribution_id);
+            }
+            return Ok(())
+        }
+
+        let endpoint = optional("cloudfront-endpoint")
+            .and_then(|e| e.as_str())
+            .unwrap_or("https://cloudfront.amazonaws.com");
+        let timeout = optional("invalidation-timeout")
+            .and_then(|t| t.as_integer())
+            .unwrap_or(1200);
+        let client = cloudfront::CloudFront::new(self.dist("aws-access-key-id")?,
+                                                 self.dist("aws-secret-key")?,
+                                                 endpoint,
+                                                 Duration::from_secs(timeout as u64));
+        for id in client.invalidate(distribution_id, &paths)? {
+            self.report.invalidations.push((distribution_id.to_string(), id));
+        }
+        Ok(())
     }
 
     fn rust_dir(&self) -> PathBuf {
         self.work.join("rust")
     }
 
+    /// Scratch space of this channel, which no other channel touches.
+    fn channel_dir(&self) -> PathBuf {
+        self.work.join(&self.release)
+    }
+
     fn dl_dir(&self) -> PathBuf {
-        self.work.join("dl")
+        self.channel_dir().join("dl")
+    }
+
+    /// Fails unless the filesystem holding the work directory has room for
+    /// about `needed` more bytes for `what`, while keeping
+    /// `dist.min-free-gb` gigabytes free.
+    fn ensure_space(&self, what: &str, needed: u64) -> Result<()> {
+        let reserve = self.min_free();
+        let available = t!(fs2::available_space(&self.work));
+        println!("{} needs about {} bytes, {} available", what, needed, available);
+        if available < needed.saturating_add(reserve) {
+            return Err(Error::DiskSpace(format!(
+                "{} needs about {} bytes and {} more are kept free, but only {} are \
+                 available in {}",
+                what, needed, reserve, available, self.work.display())))
+        }
+        Ok(())
+    }
+
+    /// Removes the directory `dir` from the work directory if it's there,
+    /// reporting how much space that freed.
+    fn clean(&mut self, dir: &Path) -> Result<()> {
+        if !dir.exists() {
+            return Ok(())
+        }
+        let bytes = files_in(dir)?.iter()
+            .map(|f| f.symlink_metadata().map(|m| m.len()).unwrap_or(0))
+            .sum::<u64>();
+        t!(fs::remove_dir_all(dir));
+        println!("removed {} ({} bytes)", dir.display(), bytes);
+        self.report.cleaned.push((dir.display().to_string(), bytes));
+        Ok(())
+    }
+
+    /// The cache of artifacts downloaded from CI, limited to
+    /// `dist.download-cache-gb` gigabytes.
+    fn cache(&self) -> Cache {
+        let gb = self.secrets.get("dist")
+            .and_then(|d| d.get("download-cache-gb"))
+            .and_then(|v| v.as_integer())
+            .unwrap_or(50);
+        Cache::new(self.work.join("cache"),
+                   gb.max(0) as u64 * 1024 * 1024 * 1024,
+                   self.min_free())
+    }
+
+    /// Bytes kept free in the work directory, `dist.min-free-gb`.
+    fn min_free(&self) -> u64 {
+        self.secrets.get("dist")
+            .and_then(|d| d.get("min-free-gb"))
+            .and_then(|v| v.as_integer())
+            .unwrap_or(1)
+            .max(0) as u64 * 1024 * 1024 * 1024
+    }
+
+    /// How many artifacts are downloaded at once, `dist.s3-parallelism`.
+    fn download_parallelism(&self) -> usize {
+        self.secrets.get("dist")
+            .and_then(|d| d.get("s3-parallelism"))
+            .and_then(|v| v.as_integer())
+            .unwrap_or(8) as usize
     }
 
     fn build_dir(&self) -> PathBuf {
-        self.work.join("build")
+        self.channel_dir().join("build")
+    }
+
+    /// Where manifests, hashes and signatures are generated.
+    fn dist_dir(&self) -> PathBuf {
+        self.build_dir().join("build/dist")
     }
 
-    fn aws_s3(&self) -> Command {
-        let mut cmd = Command::new("aws");
-        cmd.arg("s3");
-        self.aws_creds(&mut cmd);
-        return cmd
+    /// The version appearing in the names of this release's tarballs, which
+    /// depends on the channel they were built for. That's not necessarily the
+    /// channel we're releasing, e.g. when releasing a stable build to dev.
+    fn tarball_version(&self) -> String {
+        let version = self.current_version.as_ref().unwrap();
+        match version.pre {
+            Pre::Stable => version.number(),
+            Pre::Beta(_) => "beta".to_string(),
+            Pre::Nightly => "nightly".to_string(),
+            Pre::Dev => "dev".to_string(),
+        }
     }
 
-    fn aws_creds(&self, cmd: &mut Command) {
-        let access = self.secrets["dist"]["aws-access-key-id"].as_str().unwrap();
-        let secret = self.secrets["dist"]["aws-secret-key"].as_str().unwrap();
-        cmd.env("AWS_ACCESS_KEY_ID", &access)
-           .env("AWS_SECRET_ACCESS_KEY", &secret);
+    /// Whether manifests are built by `x.py dist hash-and-sign` from a rust
+    /// checkout rather than natively.
+    fn use_xpy(&self) -> Result<bool> {
+        let builder = self.secrets.get("dist")
+            .and_then(|d| d.get("build-manifest"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("x.py");
+        match builder {
+            "native" => Ok(false),
+            "x.py" => Ok(true),
+            s => Err(Error::Config(format!("unknown manifest builder: {}", s))),
+        }
     }
 
-    fn download_manifest(&mut self) -> toml::Value {
-        t!(self.handle.get(true));
-        let addr = self.secrets["dist"]["upload-addr"].as_str().unwrap();
-        let upload_dir = self.secrets["dist"]["upload-dir"].as_str().unwrap();
+    fn public_key(&self) -> Result<pgp::PublicKey> {
+        let key = self.secrets.get("dist")
+            .and_then(|d| d.get("gpg-public-key"))
+            .and_then(|v| v.as_str());
+        let key = match key {
+            Some(key) => key,
+            None => self.dist("gpg-key")?,
+        };
+        pgp::PublicKey::load(&self.channel_dir().join("gnupg-verify"), Path::new(key))
+    }
+
+    /// Looks up the string `key` in the `[dist]` section of the secrets, or in
+    /// `[dist.dev]` first when releasing the dev channel.
+    fn dist(&self, key: &str) -> Result<&str> {
+        match self.dev_dist(key) {
+            Some(value) => Ok(value),
+            None => storage::dist(&self.secrets, key),
+        }
+    }
+
+    fn dev_dist(&self, key: &str) -> Option<&str> {
+        if self.release != "dev" {
+            return None
+        }
+        self.secrets.get("dist")
+            .and_then(|d| d.get("dev"))
+            .and_then(|d| d.get(key))
+            .and_then(|v| v.as_str())
+    }
+
+    fn download_manifest(&mut self) -> Result<toml::Value> {
         let url = format!("{}/{}/channel-rust-{}.toml",
-                          addr,
-                          upload_dir,
+                          self.dist("upload-addr")?,
+                          self.dist("upload-dir")?,
                           self.release);
         println!("downloading manifest from: {}", url);
-        t!(self.handle.url(&url));
         let mut result = Vec::new();
+        self.fetch(&url, |data| result.extend_from_slice(data))?;
+        String::from_utf8(result).ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| Error::Manifest(format!("{} is not valid TOML", url)))
+    }
+
+    /// Asks the GitHub API at `dist.github-api-url` for the commit at the tip
+    /// of `branch` of `dist.github-repo`.
+    fn branch_head(&mut self, branch: &str) -> Result<String> {
+        let optional = |key| {
+            self.secrets.get("dist").and_then(|d| d.get(key)).and_then(|v| v.as_str())
+        };
+        let api = optional("github-api-url").unwrap_or("https://api.github.com");
+        let url = format!("{}/repos/{}/git/ref/heads/{}",
+                          api.trim_end_matches('/'),
+                          optional("github-repo").unwrap_or("rust-lang/rust"),
+                          branch);
+        let mut headers = List::new();
+        headers.append("Accept: application/vnd.github+json")?;
+        headers.append("User-Agent: promote-release")?;
+        if let Some(token) = optional("github-token") {
+            headers.append(&format!("Authorization: token {}", token))?;
+        }
+
+        println!("asking {} for the head of {}", url, branch);
+        self.handle.http_headers(headers)?;
+        let mut body = Vec::new();
+        let res = self.fetch(&url, |data| body.extend_from_slice(data));
+        // The handle is shared, so don't send these anywhere else.
+        self.handle.http_headers(List::new())?;
+        res?;
+
+        let json: serde_json::Value = serde_json::from_slice(&body).map_err(|e| {
+            Error::Network(format!("{} returned invalid JSON: {}", url, e))
+        })?;
+        match json["object"]["sha"].as_str() {
+            Some(sha) => Ok(sha.to_string()),
+            None => Err(Error::Network(format!("no commit for {} in {}", branch, url))),
+        }
+    }
+
+    /// Downloads `url`, passing its contents to `f` as they arrive.
+    fn fetch<F: FnMut(&[u8])>(&mut self, url: &str, mut f: F) -> Result<()> {
+        self.handle.get(true)?;
+        self.handle.url(url)?;
         {
             let mut t = self.handle.transfer();
-
-            t!(t.write_function(|data| {
-                result.extend_from_slice(data);
+            t.write_function(|data| {
+                f(data);
                 Ok(data.len())
-            }));
-            t!(t.perform());
+            })?;
+            t.perform()?;
+        }
+        let code = self.handle.response_code()?;
+        if code != 200 {
+            return Err(Error::Network(format!("{} returned {}", url, code)))
+        }
+        Ok(())
+    }
+
+    /// Checks that what's being served for the channel is what we just
+    /// released: the live manifest must be the one we generated, every
+    /// artifact it lists must match what we uploaded, and a sample of the
+    /// artifacts and their signatures are downloaded and checked too.
+    fn verify_published(&mut self) -> Result<()> {
+        if self.dry_run {
+            println!("would verify the published {} channel", self.release);
+            return Ok(())
+        }
+        let version = self.current_version.clone().unwrap();
+
+        // The CDN may take a little while to pick up the invalidation.
+        let mut attempts = 0;
+        let manifest = loop {
+            let manifest = self.download_manifest()?;
+            let live = rust_version(&manifest)?;
+            if live == version {
+                break manifest
+            }
+            attempts += 1;
+            if attempts == 10 {
+                return Err(Error::Verification(format!(
+                    "live manifest has version {}, expected {}", live, version)))
+            }
+            println!("live manifest has version {}, waiting for the CDN", live);
+            thread::sleep(Duration::from_secs(30));
+        };
+
+        let local = self.dl_dir().join(format!("channel-rust-{}.toml", self.release));
+        let mut contents = String::new();
+        t!(t!(File::open(&local)).read_to_string(&mut contents));
+        if contents.parse::<toml::Value>().ok() != Some(manifest.clone()) {
+            return Err(Error::Verification(format!(
+                "live manifest differs from {}", local.display())))
         }
-        assert_eq!(t!(self.handle.response_code()), 200);
-        t!(t!(String::from_utf8(result)).parse())
+
+        // Every artifact listed must be one we uploaded, byte for byte.
+        let artifacts = manifest_artifacts(&manifest);
+        for (url, hash) in &artifacts {
+            let name = url_file_name(url)?;
+            let file = self.dl_dir().join(name);
+            if !file.exists() {
+                return Err(Error::Verification(format!("{} wasn't uploaded", url)))
+            }
+            if self.hashes.sha256(&file)? != *hash {
+                return Err(Error::Verification(format!("{} doesn't match the manifest", name)))
+            }
+        }
+        println!("all {} artifacts in the live manifest match", artifacts.len());
+
+        let sample = self.secrets.get("dist")
+            .and_then(|d| d.get("verify-sample"))
+            .and_then(|v| v.as_integer())
+            .unwrap_or(3) as usize;
+        let key = self.public_key()?;
+        let mut rng = rand::thread_rng();
+        for (url, hash) in artifacts.choose_multiple(&mut rng, sample) {
+            println!("downloading {}", url);
+            let mut sha256 = Sha256::new();
+            self.fetch(url, |data| sha256.input(data))?;
+            if hex(&sha256.result()) != *hash {
+                return Err(Error::Verification(format!("{} doesn't match the manifest", url)))
+            }
+            let mut signature = Vec::new();
+            self.fetch(&format!("{}.asc", url), |data| signature.extend_from_slice(data))?;
+            let name = url_file_name(url)?;
+            key.verify(&self.dl_dir().join(name), &String::from_utf8_lossy(&signature))
+                .map_err(|e| Error::Verification(format!("{}.asc: {}", url, e)))?;
+        }
+        Ok(())
     }
 }
 
-fn run(cmd: &mut Command) {
+/// Whether `s` looks like a `YYYY-MM-DD` date.
+fn is_date(s: &str) -> bool {
+    s.len() == 10 && s.char_indices().all(|(i, c)| {
+        if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() }
+    })
+}
+
+/// Reads and parses the channel manifest at `path`.
+fn read_manifest(path: &Path) -> Result<toml::Value> {
+    let mut contents = String::new();
+    t!(t!(File::open(path)).read_to_string(&mut contents));
+    contents.parse().map_err(|e| Error::Manifest(format!("{}: {}", path.display(), e)))
+}
+
+/// The version of the `rust` package in a channel manifest.
+fn rust_version(manifest: &toml::Value) -> Result<Version> {
+    let version = manifest.get("pkg")
+        .and_then(|pkg| pkg.get("rust"))
+        .and_then(|rust| rust.get("version"))
+        .and_then(|version| version.as_str())
+        .ok_or_else(|| Error::Manifest("`pkg.rust.version` not a string".to_string()))?;
+    Version::parse(version)
+}
+
+/// The url and sha256 of every artifact listed in a channel manifest.
+fn manifest_artifacts(manifest: &toml::Value) -> Vec<(String, String)> {
+    let mut artifacts = Vec::new();
+    let pkgs = manifest.get("pkg").and_then(|p| p.as_table());
+    for pkg in pkgs.into_iter().flat_map(|p| p.values()) {
+        let targets = pkg.get("target").and_then(|t| t.as_table());
+        for target in targets.into_iter().flat_map(|t| t.values()) {
+            for &(url, hash) in &[("url", "hash"), ("xz_url", "xz_hash")] {
+                let url = target.get(url).and_then(|u| u.as_str());
+                let hash = target.get(hash).and_then(|h| h.as_str());
+                if let (Some(url), Some(hash)) = (url, hash) {
+                    artifacts.push((url.to_string(), hash.to_string()));
+                }
+            }
+        }
+    }
+    artifacts
+}
+
+/// The file name at the end of the artifact `url` from a manifest.
+fn url_file_name(url: &str) -> Result<&str> {
+    match url.rfind('/') {
+        Some(i) => Ok(&url[i + 1..]),
+        None => Err(Error::Manifest(format!("artifact url {} has no file name", url))),
+    }
+}
+
+/// Removes the file `path` unless it doesn't exist.
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        res => t!(res),
+    }
+    Ok(())
+}
+
+/// The directory in the docs `tarball` of `package` which holds the HTML.
+fn docs_html(tarball: &Path, package: &str) -> Result<String> {
+    let prefix = file_name(tarball)?.trim_end_matches(".tar.gz");
+    Ok(format!("{}/{}/share/doc/rust/html", prefix, package))
+}
+
+/// Where CI uploads the artifacts built from `rev`.
+fn ci_prefix(rev: &str) -> String {
+    format!("s3://rust-lang-ci2/rustc-builds/{}/", rev)
+}
+
+fn channels_switching() -> Error {
+    Error::NotReady("looks like channels are being switched -- was this branch just \
+                     created and has a pending PR to change the release \
+                     channel?".to_string())
+}
+
+fn not_ready() -> Error {
+    Error::NotReady("appears that this rev doesn't have any artifacts, is this a \
+                     stable/beta branch awaiting a PR?".to_string())
+}
+
+/// Notes that the release is being skipped because of `reason`.
+fn skip(reason: &'static str) -> Outcome {
+    println!("{}, skipping", reason);
+    Outcome::Skipped(reason)
+}
+
+/// Prints every object that a recursive copy of `src` to the S3 prefix `dst`
+/// would write, used in place of the actual upload during a dry run.
+fn plan_copy(src: &Path, dst: &str) -> Result<()> {
+    for file in files_in(src)? {
+        let key = storage::key_for(file.strip_prefix(src).unwrap());
+        println!("would upload {} to {}{}", file.display(), dst, key);
+    }
+    Ok(())
+}
+
+/// The name of the file at `path`, which we only ever give UTF-8 names.
+fn file_name(path: &Path) -> Result<&str> {
+    path.file_name().and_then(|name| name.to_str()).ok_or_else(|| {
+        Error::Io(format!("file name of {}", path.display()),
+                  io::Error::new(io::ErrorKind::InvalidData, "not a UTF-8 file name"))
+    })
+}
+
+/// Returns all files underneath `dir`, recursively, in a stable order.
+fn files_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in t!(dir.read_dir()) {
+        let path = t!(entry).path();
+        if path.is_dir() {
+            files.extend(files_in(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Writes `json` to `path`, pretty-printed.
+fn write_json(path: &Path, json: &serde_json::Value) -> Result<()> {
+    // Serializing a `Value` can't fail.
+    let contents = serde_json::to_string_pretty(json).unwrap();
+    t!(t!(File::create(path)).write_all(format!("{}\n", contents).as_bytes()));
+    Ok(())
+}
+
+/// Runs `f` on every item using up to `threads` threads, stopping at the
+/// first error.
+fn parallel<T, F>(threads: usize, items: Vec<T>, f: F) -> Result<()>
+    where T: Send, F: Fn(T) -> Result<()> + Sync
+{
+    let queue = Mutex::new(items.into_iter());
+    let error = Mutex::new(None);
+    let res = scope(|s| {
+        for _ in 0..threads.max(1) {
+            s.spawn(|_| {
+                while error.lock().unwrap().is_none() {
+                    let item = queue.lock().unwrap().next();
+                    let item = match item {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    if let Err(e) = f(item) {
+                        error.lock().unwrap().get_or_insert(e);
+                    }
+                }
+            });
+        }
+    });
+    if let Err(payload) = res {
+        panic::resume_unwind(payload);
+    }
+    match error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn run(cmd: &mut Command) -> Result<()> {
     println!("running {:?}", cmd);
-    let status = t!(cmd.status());
+    let status = cmd.status().map_err(|e| {
+        Error::Command(format!("failed to run {:?}: {}", cmd, e))
+    })?;
     if !status.success() {
-        panic!("failed command:{:?}\n:{}", cmd, status);
+        return Err(Error::command(cmd, status, ""))
     }
+    Ok(())
 }
 
-fn output(cmd: &mut Command) -> String {
+fn output(cmd: &mut Command) -> Result<String> {
     println!("running {:?}", cmd);
-    let output = t!(cmd.output());
+    let output = cmd.output().map_err(|e| {
+        Error::Command(format!("failed to run {:?}: {}", cmd, e))
+    })?;
     if !output.status.success() {
-        panic!("failed command:{:?}\n:{}\n\n{}\n\n{}", cmd, output.status,
-               String::from_utf8_lossy(&output.stdout),
-               String::from_utf8_lossy(&output.stderr),);
+        let printed = format!("\n\n{}\n\n{}",
+                              String::from_utf8_lossy(&output.stdout),
+                              String::from_utf8_lossy(&output.stderr));
+        return Err(Error::command(cmd, output.status, &printed))
     }
 
-    String::from_utf8(output.stdout).unwrap()
+    String::from_utf8(output.stdout).map_err(|_| {
+        Error::Command(format!("{:?} printed invalid UTF-8", cmd))
+    })
 }
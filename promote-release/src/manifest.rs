@@ -0,0 +1,441 @@
+//! Generation of channel manifests, replacing `x.py dist hash-and-sign` from
+//! the rust repository (`src/tools/build-manifest`).
+//!
+//! Everything is derived from the tarballs which were downloaded: file names
+//! tell us which packages exist for which targets and the `version` file
+//! inside each package tells us its version.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use tar::Archive;
+use toml::Value;
+use toml::value::Table;
+
+use errors::{Error, Result};
+use hashes::Hashes;
+use {file_name, files_in, hex};
+
+/// Packages which are still shipped under a `-preview` name in the manifest,
+/// with a rename so `rustup component add rls` keeps working.
+static PREVIEW: &[(&str, &str)] = &[
+    ("clippy", "clippy-preview"),
+    ("llvm-tools", "llvm-tools-preview"),
+    ("miri", "miri-preview"),
+    ("rls", "rls-preview"),
+    ("rustfmt", "rustfmt-preview"),
+];
+
+/// Packages which make up the combined `rust` package for a host.
+static COMPONENTS: &[&str] = &["rustc", "rust-std", "cargo", "rust-docs", "rust-mingw"];
+
+/// Packages which aren't offered to rustup users at all.
+static UNLISTED: &[&str] = &["rust", "rustc-docs"];
+
+static PROFILES: &[(&str, &[&str])] = &[
+    ("minimal", &["rustc", "cargo", "rust-std", "rust-mingw"]),
+    ("default", &["rustc", "cargo", "rust-std", "rust-mingw", "rust-docs",
+                  "rustfmt-preview", "clippy-preview"]),
+    ("complete", &["rustc", "cargo", "rust-std", "rust-mingw", "rust-docs",
+                   "rustfmt-preview", "clippy-preview", "rls-preview",
+                   "rust-analysis", "llvm-tools-preview", "rust-src",
+                   "miri-preview", "rustc-dev"]),
+];
+
+pub struct Builder<'a> {
+    /// Directory containing all of the tarballs of the release.
+    pub dl: &'a Path,
+    /// Directory to write the manifests and hashes to.
+    pub out: &'a Path,
+    pub channel: &'a str,
+    /// The version appearing in tarball names, `nightly`, `beta` or the full
+    /// version number for stable.
+    pub version: &'a str,
+    pub date: &'a str,
+    pub rev: &'a str,
+    /// Base URL the tarballs are served from, without the date.
+    pub url: &'a str,
+    pub hashes: &'a Hashes,
+}
+
+/// Tarballs found for a package, by target, as `(gz, xz)`.
+type Found<'a> = BTreeMap<&'a str, BTreeMap<&'a str, (Option<&'a Tarball>, Option<&'a Tarball>)>>;
+
+/// A tarball of one package for one target.
+struct Tarball {
+    pkg: String,
+    target: String,
+    xz: bool,
+    name: String,
+    hash: String,
+}
+
+impl<'a> Builder<'a> {
+    /// Hashes every tarball, writing `*.sha256` files next to the manifest,
+    /// and writes out the channel manifest. Returns the paths of the
+    /// manifests written.
+    pub fn build(&self) -> Result<Vec<String>> {
+        let mut tarballs = Vec::new();
+        for file in files_in(self.dl)? {
+            let name = file_name(&file)?.to_string();
+            let (pkg, target, xz) = match parse(&name, self.version) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let hash = self.hashes.sha256(&file)?;
+            self.write(&format!("{}.sha256", name), &format!("{}  {}\n", hash, name))?;
+            tarballs.push(Tarball { pkg, target, xz, name, hash });
+        }
+
+        let manifest = self.manifest(&tarballs)?;
+        let manifest = toml::to_string(&manifest).map_err(|e| {
+            Error::Manifest(format!("failed to serialize manifest: {}", e))
+        })?;
+
+        // Stable releases are also available by their version number, e.g.
+        // `1.40` and `1.40.0`.
+        let mut names = vec![self.channel.to_string()];
+        if self.channel == "stable" {
+            names.push(self.version.rsplit_once('.').unwrap().0.to_string());
+            names.push(self.version.to_string());
+        }
+        let mut manifests = Vec::new();
+        for name in names {
+            let file = format!("channel-rust-{}.toml", name);
+            self.write(&file, &manifest)?;
+            self.write(&format!("{}.sha256", file),
+                       &format!("{}  {}\n", hex(&Sha256::digest(manifest.as_bytes())), file))?;
+            self.write(&format!("channel-rust-{}-date.txt", name), self.date)?;
+            self.write(&format!("channel-rust-{}-git-commit-hash.txt", name), self.rev)?;
+            manifests.push(file);
+        }
+        Ok(manifests)
+    }
+
+    fn manifest(&self, tarballs: &[Tarball]) -> Result<Value> {
+        let url = |name: &str| format!("{}/{}/{}", self.url, self.date, name);
+
+        // pkg -> target -> (gz, xz)
+        let mut found = Found::new();
+        for tarball in tarballs {
+            let entry = found.entry(&tarball.pkg[..])
+                .or_default()
+                .entry(&tarball.target[..])
+                .or_insert((None, None));
+            if tarball.xz {
+                entry.1 = Some(tarball);
+            } else {
+                entry.0 = Some(tarball);
+            }
+        }
+        if !found.contains_key("rust") {
+            return Err(Error::MissingComponents(vec!["rust".to_string()]))
+        }
+
+        let mut pkgs = Table::new();
+        for (pkg, targets) in found.iter() {
+            let mut target_table = Table::new();
+            for (target, &(gz, xz)) in targets.iter() {
+                let mut t = Table::new();
+                t.insert("available".to_string(), Value::Boolean(true));
+                if let Some(gz) = gz {
+                    t.insert("url".to_string(), Value::String(url(&gz.name)));
+                    t.insert("hash".to_string(), Value::String(gz.hash.clone()));
+                }
+                if let Some(xz) = xz {
+                    t.insert("xz_url".to_string(), Value::String(url(&xz.name)));
+                    t.insert("xz_hash".to_string(), Value::String(xz.hash.clone()));
+                }
+                if *pkg == "rust" {
+                    let (components, extensions) = self.rust_components(&found, target);
+                    t.insert("components".to_string(), components);
+                    t.insert("extensions".to_string(), extensions);
+                }
+                target_table.insert(target.to_string(), Value::Table(t));
+            }
+
+            let version = match targets.values().filter_map(|&(gz, _)| gz).next() {
+                Some(gz) => package_version(&self.dl.join(&gz.name))?,
+                None => String::new(),
+            };
+            let mut pkg_table = Table::new();
+            pkg_table.insert("version".to_string(), Value::String(version));
+            pkg_table.insert("target".to_string(), Value::Table(target_table));
+            pkgs.insert(manifest_name(pkg).to_string(), Value::Table(pkg_table));
+        }
+
+        let mut renames = Table::new();
+        for &(from, to) in PREVIEW {
+            let mut rename = Table::new();
+            rename.insert("to".to_string(), Value::String(to.to_string()));
+            renames.insert(from.to_string(), Value::Table(rename));
+        }
+
+        let mut profiles = Table::new();
+        for &(name, list) in PROFILES {
+            let list = list.iter()
+                .filter(|pkg| pkgs.contains_key(**pkg))
+                .map(|pkg| Value::String(pkg.to_string()))
+                .collect();
+            profiles.insert(name.to_string(), Value::Array(list));
+        }
+
+        let mut manifest = Table::new();
+        manifest.insert("manifest-version".to_string(), Value::String("2".to_string()));
+        manifest.insert("date".to_string(), Value::String(self.date.to_string()));
+        manifest.insert("pkg".to_string(), Value::Table(pkgs));
+        manifest.insert("renames".to_string(), Value::Table(renames));
+        manifest.insert("profiles".to_string(), Value::Table(profiles));
+        Ok(Value::Table(manifest))
+    }
+
+    /// Returns the `components` and `extensions` arrays of the `rust` package
+    /// for `host`.
+    fn rust_components(&self,
+                       found: &Found,
+                       host: &str) -> (Value, Value) {
+        let entry = |pkg: &str, target: &str| {
+            let mut t = Table::new();
+            t.insert("pkg".to_string(), Value::String(manifest_name(pkg).to_string()));
+            t.insert("target".to_string(), Value::String(target.to_string()));
+            Value::Table(t)
+        };
+        let has = |pkg: &str, target: &str| {
+            found.get(pkg).is_some_and(|targets| targets.contains_key(target))
+        };
+
+        let components = COMPONENTS.iter()
+            .filter(|pkg| has(pkg, host))
+            .map(|pkg| entry(pkg, host))
+            .collect::<Vec<_>>();
+
+        let mut extensions = Vec::new();
+        let mut seen = BTreeSet::new();
+        for (pkg, targets) in found.iter() {
+            if UNLISTED.contains(pkg) {
+                continue
+            }
+            for target in targets.keys() {
+                let is_component = *target == host && COMPONENTS.contains(pkg);
+                // Host-specific tools are only offered for their own host,
+                // while the standard library and its analysis data can be
+                // added for any target.
+                let cross = *pkg == "rust-std" || *pkg == "rust-analysis";
+                if is_component || !(*target == host || *target == "*" || cross) {
+                    continue
+                }
+                if seen.insert((*pkg, *target)) {
+                    extensions.push(entry(pkg, target));
+                }
+            }
+        }
+        (Value::Array(components), Value::Array(extensions))
+    }
+
+    fn write(&self, name: &str, contents: &str) -> Result<()> {
+        let path = self.out.join(name);
+        t!(t!(File::create(&path)).write_all(contents.as_bytes()));
+        Ok(())
+    }
+}
+
+/// Splits a tarball name like `rust-std-nightly-x86_64-apple-darwin.tar.xz`
+/// into its package, target and whether it's the xz variant. `version` is
+/// the version appearing in tarball names, see `Builder::version`.
+pub fn parse(name: &str, version: &str) -> Option<(String, String, bool)> {
+    let (base, xz) = if let Some(base) = name.strip_suffix(".tar.gz") {
+        (base, false)
+    } else if let Some(base) = name.strip_suffix(".tar.xz") {
+        (base, true)
+    } else {
+        return None
+    };
+    let infix = format!("-{}-", version);
+    let suffix = format!("-{}", version);
+    if let Some(i) = base.find(&infix) {
+        Some((base[..i].to_string(), base[i + infix.len()..].to_string(), xz))
+    } else {
+        base.strip_suffix(&suffix[..]).map(|pkg| (pkg.to_string(), "*".to_string(), xz))
+    }
+}
+
+fn manifest_name(pkg: &str) -> &str {
+    PREVIEW.iter()
+        .find(|&&(from, _)| from == pkg)
+        .map_or(pkg, |&(_, to)| to)
+}
+
+/// Reads the contents of the `version` file at the top of a package tarball.
+pub fn package_version(tarball: &Path) -> Result<String> {
+    let mut archive = Archive::new(GzDecoder::new(t!(File::open(tarball))));
+    for entry in t!(archive.entries()) {
+        let mut entry = t!(entry);
+        let is_version = match t!(entry.path()).iter().nth(1) {
+            Some(path) => path == Path::new("version"),
+            None => false,
+        };
+        if is_version {
+            let mut contents = String::new();
+            t!(entry.read_to_string(&mut contents));
+            return Ok(contents.trim().to_string())
+        }
+    }
+    Err(Error::Manifest(format!("no version file in {}", tarball.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use toml::Value;
+
+    use errors::Error;
+    use hashes::{self, Hashes};
+    use tests::{tarball, TempDir};
+    use super::{parse, Builder};
+
+    const HOST: &str = "x86_64-unknown-linux-gnu";
+    const VERSION: &str = "1.42.0-nightly (0d2817a43 2019-12-16)";
+
+    /// Fills `dl` with the tarballs of a small nightly.
+    fn nightly(dl: &Path) {
+        let packages = [
+            ("rust", HOST),
+            ("rustc", HOST),
+            ("rust-std", HOST),
+            ("rust-std", "wasm32-unknown-unknown"),
+            ("cargo", HOST),
+            ("clippy", HOST),
+            ("rustc-docs", HOST),
+        ];
+        for &(pkg, target) in packages.iter() {
+            let top = format!("{}-nightly-{}", pkg, target);
+            tarball(&dl.join(format!("{}.tar.gz", top)), &top, VERSION);
+        }
+        tarball(&dl.join("rust-src-nightly.tar.gz"), "rust-src-nightly", VERSION);
+        fs::write(dl.join(format!("rust-nightly-{}.tar.xz", HOST)), "xz").unwrap();
+        fs::write(dl.join("README.md"), "not a tarball").unwrap();
+    }
+
+    fn build(dl: &Path, channel: &str, version: &str) -> Result<Vec<String>, Error> {
+        Builder {
+            dl,
+            out: dl,
+            channel,
+            version,
+            date: "2019-12-16",
+            rev: "0d2817a43f72d0ba0e1ab11a89ba7a4c5e42b7b9",
+            url: "https://static.rust-lang.org/dist",
+            hashes: &Hashes::new(),
+        }.build()
+    }
+
+    fn strings(value: &Value) -> Vec<String> {
+        value.as_array().unwrap().iter().map(|s| s.as_str().unwrap().to_string()).collect()
+    }
+
+    /// `pkg@target` for each entry of a `components` or `extensions` array.
+    fn entries(value: &Value) -> Vec<String> {
+        value.as_array().unwrap().iter().map(|e| {
+            format!("{}@{}", e["pkg"].as_str().unwrap(), e["target"].as_str().unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn parses_tarball_names() {
+        assert_eq!(parse("rust-std-nightly-x86_64-apple-darwin.tar.xz", "nightly"),
+                   Some(("rust-std".to_string(), "x86_64-apple-darwin".to_string(), true)));
+        assert_eq!(parse("rustc-1.40.0-x86_64-pc-windows-msvc.tar.gz", "1.40.0"),
+                   Some(("rustc".to_string(), "x86_64-pc-windows-msvc".to_string(), false)));
+        assert_eq!(parse("rust-src-beta.tar.gz", "beta"),
+                   Some(("rust-src".to_string(), "*".to_string(), false)));
+        assert_eq!(parse("rust-src-beta.tar.gz", "nightly"), None);
+        assert_eq!(parse("rustc-nightly-src.tar.xz.sha256", "nightly"), None);
+        assert_eq!(parse("channel-rust-nightly.toml", "nightly"), None);
+    }
+
+    #[test]
+    fn builds_manifest() {
+        let dir = TempDir::new("manifest");
+        let dl = dir.path();
+        nightly(dl);
+        assert_eq!(build(dl, "nightly", "nightly").unwrap(), ["channel-rust-nightly.toml"]);
+
+        let contents = fs::read_to_string(dl.join("channel-rust-nightly.toml")).unwrap();
+        let manifest = contents.parse::<Value>().unwrap();
+        assert_eq!(manifest["manifest-version"].as_str(), Some("2"));
+        assert_eq!(manifest["date"].as_str(), Some("2019-12-16"));
+
+        let pkg = manifest["pkg"].as_table().unwrap();
+        assert_eq!(pkg.keys().collect::<Vec<_>>(),
+                   ["cargo", "clippy-preview", "rust", "rust-src", "rust-std", "rustc",
+                    "rustc-docs"]);
+        assert_eq!(pkg["rustc"]["version"].as_str(), Some(VERSION));
+
+        let rust = &pkg["rust"]["target"][HOST];
+        let gz = format!("rust-nightly-{}.tar.gz", HOST);
+        let xz = format!("rust-nightly-{}.tar.xz", HOST);
+        let sha256 = |name: &str| hashes::digests(&dl.join(name)).unwrap().sha256;
+        assert_eq!(rust["available"].as_bool(), Some(true));
+        assert_eq!(rust["url"].as_str().unwrap(),
+                   format!("https://static.rust-lang.org/dist/2019-12-16/{}", gz));
+        assert_eq!(rust["hash"].as_str().unwrap(), sha256(&gz));
+        assert_eq!(rust["xz_url"].as_str().unwrap(),
+                   format!("https://static.rust-lang.org/dist/2019-12-16/{}", xz));
+        assert_eq!(rust["xz_hash"].as_str().unwrap(), sha256(&xz));
+        assert_eq!(entries(&rust["components"]),
+                   [format!("rustc@{}", HOST), format!("rust-std@{}", HOST),
+                    format!("cargo@{}", HOST)]);
+        assert_eq!(entries(&rust["extensions"]),
+                   [format!("clippy-preview@{}", HOST), "rust-src@*".to_string(),
+                    "rust-std@wasm32-unknown-unknown".to_string()]);
+        assert!(pkg["rust-std"]["target"]["wasm32-unknown-unknown"].get("xz_url").is_none());
+
+        let renames = manifest["renames"].as_table().unwrap();
+        assert_eq!(renames.len(), 5);
+        assert_eq!(renames["rls"]["to"].as_str(), Some("rls-preview"));
+
+        let profiles = &manifest["profiles"];
+        assert_eq!(strings(&profiles["minimal"]), ["rustc", "cargo", "rust-std"]);
+        assert_eq!(strings(&profiles["default"]),
+                   ["rustc", "cargo", "rust-std", "clippy-preview"]);
+        assert_eq!(strings(&profiles["complete"]),
+                   ["rustc", "cargo", "rust-std", "clippy-preview", "rust-src"]);
+
+        let file = fs::read_to_string(dl.join(format!("{}.sha256", gz))).unwrap();
+        assert_eq!(file, format!("{}  {}\n", sha256(&gz), gz));
+        assert_eq!(fs::read_to_string(dl.join("channel-rust-nightly-date.txt")).unwrap(),
+                   "2019-12-16");
+        assert!(dl.join("channel-rust-nightly.toml.sha256").exists());
+        assert!(!dl.join("README.md.sha256").exists());
+    }
+
+    #[test]
+    fn names_stable_manifests_by_version() {
+        let dir = TempDir::new("manifest");
+        let dl = dir.path();
+        let top = format!("rust-1.40.0-{}", HOST);
+        tarball(&dl.join(format!("{}.tar.gz", top)), &top, "1.40.0 (73528e339 2019-12-16)");
+        assert_eq!(build(dl, "stable", "1.40.0").unwrap(),
+                   ["channel-rust-stable.toml", "channel-rust-1.40.toml",
+                    "channel-rust-1.40.0.toml"]);
+    }
+
+    #[test]
+    fn requires_rust_package() {
+        let dir = TempDir::new("manifest");
+        let dl = dir.path();
+        nightly(dl);
+        fs::remove_file(dl.join(format!("rust-nightly-{}.tar.gz", HOST))).unwrap();
+        fs::remove_file(dl.join(format!("rust-nightly-{}.tar.xz", HOST))).unwrap();
+        match build(dl, "nightly", "nightly") {
+            Err(Error::MissingComponents(missing)) => assert_eq!(missing, ["rust"]),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Detached signatures of release artifacts, made and checked by `gpg`.
+//!
+//! Every key is imported into a keyring of its own in the work directory, so
+//! nothing depends on what's in the system keyring and a signature only ever
+//! verifies if it's by the key we were configured with. Signatures are made
+//! with SHA-512, and only SHA-2 signatures are accepted.
+
+use std::fs::{self, DirBuilder};
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use errors::{Error, Result};
+
+/// `gpg`'s numbers for the hash algorithms we accept signatures made with:
+/// SHA-256, SHA-384, SHA-512 and SHA-224.
+const SHA2: &[&str] = &["8", "9", "10", "11"];
+
+/// A keyring holding a single key, removed again when dropped.
+pub struct PublicKey {
+    home: PathBuf,
+    fingerprint: String,
+}
+
+pub struct SecretKey {
+    public: PublicKey,
+    passphrase_file: PathBuf,
+}
+
+impl PublicKey {
+    /// Imports the key in `path`, public or secret, into a new keyring at
+    /// `home`, replacing whatever was there.
+    pub fn load(home: &Path, path: &Path) -> Result<PublicKey> {
+        if home.exists() {
+            t!(fs::remove_dir_all(home));
+        }
+        // gpg complains about keyrings others can read.
+        t!(DirBuilder::new().recursive(true).mode(0o700).create(home));
+        let mut key = PublicKey { home: home.to_path_buf(), fingerprint: String::new() };
+        gpg(key.gpg().arg("--import").arg(path))
+            .map_err(|e| Error::Config(format!("failed to import {}: {}", path.display(), e)))?;
+        let listing = gpg(key.gpg().arg("--with-colons").arg("--list-keys"))?;
+        key.fingerprint = listing.lines()
+            .find(|line| line.starts_with("fpr:"))
+            .and_then(|line| line.split(':').nth(9))
+            .map(|fpr| fpr.to_string())
+            .ok_or_else(|| Error::Config(format!("no key found in {}", path.display())))?;
+        Ok(key)
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Checks that the armored detached `signature` is a valid SHA-2
+    /// signature of `file` by this key.
+    pub fn verify(&self, file: &Path, signature: &str) -> Result<()> {
+        let bad = |msg: &str| Error::Signature(format!("{}: {}", file.display(), msg));
+        let mut child = t!(self.gpg()
+            .arg("--status-fd").arg("1")
+            .arg("--verify").arg("-").arg(file)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn());
+        // gpg may give up before reading all of a malformed signature.
+        drop(child.stdin.take().unwrap().write_all(signature.as_bytes()));
+        let output = t!(child.wait_with_output());
+        let status = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() {
+            return Err(bad(String::from_utf8_lossy(&output.stderr).trim()))
+        }
+
+        // [GNUPG:] VALIDSIG <fingerprint> <date> <timestamp> <expires>
+        //     <version> <reserved> <key algorithm> <hash algorithm> <class>
+        //     <primary key fingerprint>
+        let valid = status.lines()
+            .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+            .map(|line| line.split(' ').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        if valid.len() != 1 || valid[0].len() < 10 {
+            return Err(bad("expected exactly one valid signature"))
+        }
+        if valid[0][9] != self.fingerprint {
+            return Err(bad(&format!("signed by another key ({})", valid[0][9])))
+        }
+        if !SHA2.contains(&valid[0][7]) {
+            return Err(bad(&format!("signed with unaccepted hash algorithm {}", valid[0][7])))
+        }
+        Ok(())
+    }
+
+    fn gpg(&self) -> Command {
+        let mut cmd = Command::new("gpg");
+        cmd.arg("--homedir").arg(&self.home)
+           .arg("--batch")
+           .arg("--no-tty")
+           .arg("--trust-model").arg("always");
+        cmd
+    }
+}
+
+impl Drop for PublicKey {
+    fn drop(&mut self) {
+        // Importing a secret key starts an agent for the keyring.
+        drop(Command::new("gpgconf")
+            .arg("--homedir").arg(&self.home)
+            .arg("--kill").arg("gpg-agent")
+            .output());
+        drop(fs::remove_dir_all(&self.home));
+    }
+}
+
+impl SecretKey {
+    /// Imports the secret key in `path` into a new keyring at `home`. Only
+    /// the first line of `passphrase_file` is the passphrase, as with `gpg
+    /// --passphrase-file`.
+    pub fn load(home: &Path, path: &Path, passphrase_file: &Path) -> Result<SecretKey> {
+        Ok(SecretKey {
+            public: PublicKey::load(home, path)?,
+            passphrase_file: passphrase_file.to_path_buf(),
+        })
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+
+    /// Makes an armored detached signature of `file`.
+    pub fn sign(&self, file: &Path) -> Result<String> {
+        gpg(self.public.gpg()
+            .arg("--pinentry-mode").arg("loopback")
+            .arg("--passphrase-file").arg(&self.passphrase_file)
+            .arg("--local-user").arg(&self.public.fingerprint)
+            .arg("--digest-algo").arg("SHA512")
+            .arg("--armor")
+            .arg("--output").arg("-")
+            .arg("--detach-sign").arg(file))
+            .map_err(|e| Error::Signature(format!("failed to sign {}: {}", file.display(), e)))
+    }
+}
+
+/// Runs `cmd`, returning its output or what it complained about.
+fn gpg(cmd: &mut Command) -> Result<String> {
+    let Output { status, stdout, stderr } = cmd.output().map_err(|e| {
+        Error::Command(format!("failed to run {:?}: {}", cmd, e))
+    })?;
+    if !status.success() {
+        return Err(Error::Signature(String::from_utf8_lossy(&stderr).trim().to_string()))
+    }
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use errors::Error;
+    use tests::{data, TempDir};
+    use super::{PublicKey, SecretKey};
+
+    // Fixtures generated with gpg 2.2: a 2048-bit RSA key exported with
+    // `--export-secret-keys` and detached signatures of `artifact.txt` made
+    // with `--digest-algo SHA256` and `SHA1`.
+    const FINGERPRINT: &str = "B82DD802C5DD03D6DC066A91F950AFBC19655FC0";
+
+    fn public_key(dir: &TempDir) -> PublicKey {
+        PublicKey::load(&dir.path().join("gnupg"), &data("public-key.asc")).unwrap()
+    }
+
+    fn secret_key(dir: &TempDir, passphrase: &str) -> SecretKey {
+        let passphrase_file = dir.path().join("passphrase");
+        fs::write(&passphrase_file, format!("{}\nnot part of it\n", passphrase)).unwrap();
+        SecretKey::load(&dir.path().join("gnupg-secret"),
+                        &data("secret-key.asc"),
+                        &passphrase_file).unwrap()
+    }
+
+    fn assert_bad(res: Result<(), Error>, expected: &str) {
+        match res {
+            Err(Error::Signature(msg)) => assert!(msg.contains(expected), "{}", msg),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("verified"),
+        }
+    }
+
+    #[test]
+    fn loads_keys() {
+        let dir = TempDir::new("pgp-load");
+        let key = public_key(&dir);
+        assert_eq!(key.fingerprint(), FINGERPRINT);
+        assert_eq!(secret_key(&dir, "").public().fingerprint(), FINGERPRINT);
+
+        // The keyring goes away with the key.
+        drop(key);
+        assert!(!dir.path().join("gnupg").exists());
+
+        let res = PublicKey::load(&dir.path().join("gnupg"), &data("artifact.txt"));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn verifies_gpg_signature() {
+        let dir = TempDir::new("pgp-verify");
+        let signature = fs::read_to_string(data("artifact.txt.asc")).unwrap();
+        public_key(&dir).verify(&data("artifact.txt"), &signature).unwrap();
+    }
+
+    #[test]
+    fn rejects_sha1_signatures() {
+        let dir = TempDir::new("pgp-sha1");
+        let signature = fs::read_to_string(data("artifact.txt.sha1.asc")).unwrap();
+        assert_bad(public_key(&dir).verify(&data("artifact.txt"), &signature),
+                   "unaccepted hash algorithm 2");
+    }
+
+    #[test]
+    fn rejects_tampered_files() {
+        let dir = TempDir::new("pgp-tampered");
+        let tampered = dir.path().join("artifact.txt");
+        let mut contents = fs::read(data("artifact.txt")).unwrap();
+        contents[6] ^= 1;
+        fs::write(&tampered, contents).unwrap();
+        let signature = fs::read_to_string(data("artifact.txt.asc")).unwrap();
+        assert_bad(public_key(&dir).verify(&tampered, &signature), "BAD signature");
+        assert_bad(public_key(&dir).verify(&data("artifact.txt"), "not a signature"),
+                   "no valid OpenPGP data");
+    }
+
+    #[test]
+    fn signs() {
+        let dir = TempDir::new("pgp-sign");
+        let key = secret_key(&dir, "correct horse battery staple");
+        let file = data("artifact.txt");
+        let signature = key.sign(&file).unwrap();
+        assert!(signature.starts_with("-----BEGIN PGP SIGNATURE-----\n"));
+        key.public().verify(&file, &signature).unwrap();
+        // Checked against the public key on its own too.
+        public_key(&dir).verify(&file, &signature).unwrap();
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let dir = TempDir::new("pgp-passphrase");
+        match secret_key(&dir, "wrong").sign(&data("artifact.txt")) {
+            Err(Error::Signature(msg)) => assert!(msg.contains("Bad passphrase"), "{}", msg),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("signed with the wrong passphrase"),
+        }
+    }
+}
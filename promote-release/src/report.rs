@@ -0,0 +1,145 @@
+//! Machine-readable summary of a promotion, written at the end of every run
+//! so dashboards and alerting don't have to scrape our log output.
+
+use std::path::Path;
+use std::time::Instant;
+
+use serde_json;
+
+use components::Missing;
+use errors::Result;
+use {file_name, files_in, write_json};
+use hashes::Hashes;
+
+/// How a promotion ended.
+#[derive(Debug)]
+pub enum Outcome {
+    Released,
+    Skipped(&'static str),
+    /// Old releases were deleted from the archive.
+    Collected { files: usize, bytes: u64 },
+    /// Only the docs were published.
+    DocsPublished,
+    /// The channel's history was rebuilt with this many releases.
+    Indexed(usize),
+    Failed(String),
+}
+
+struct Artifact {
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+pub struct Report {
+    started: Instant,
+    started_at: String,
+    pub rev: Option<String>,
+    pub previous_version: Option<String>,
+    pub new_version: Option<String>,
+    phases: Vec<(&'static str, f64)>,
+    artifacts: Vec<Artifact>,
+    pub missing: Vec<Missing>,
+    /// Size of what CI uploaded for the rev which wasn't in the download
+    /// cache, as listed before downloading it.
+    pub download_bytes: Option<u64>,
+    /// Changes since the previous release, see `diff.rs`.
+    pub diff: Option<serde_json::Value>,
+    /// `(distribution, id)` of every CloudFront invalidation created.
+    pub invalidations: Vec<(String, String)>,
+    /// `(path, bytes)` of everything removed from the work directory.
+    pub cleaned: Vec<(String, u64)>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report {
+            started: Instant::now(),
+            started_at: time::now_utc().rfc3339().to_string(),
+            rev: None,
+            previous_version: None,
+            new_version: None,
+            phases: Vec::new(),
+            artifacts: Vec::new(),
+            missing: Vec::new(),
+            download_bytes: None,
+            diff: None,
+            invalidations: Vec::new(),
+            cleaned: Vec::new(),
+        }
+    }
+
+    /// Records that the phase `name` ran, having started at `start`.
+    pub fn phase(&mut self, name: &'static str, start: Instant) {
+        self.phases.push((name, secs(start)));
+    }
+
+    /// Records every file in `dir`, with its size and sha256, as an artifact
+    /// of this release.
+    pub fn artifacts(&mut self, dir: &Path, hashes: &Hashes) -> Result<()> {
+        for file in files_in(dir)? {
+            self.artifacts.push(Artifact {
+                name: file_name(&file)?.to_string(),
+                size: t!(file.metadata()).len(),
+                sha256: hashes.sha256(&file)?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes out the report for a run of `channel` which ended with
+    /// `outcome`.
+    pub fn write(&self, path: &Path, channel: &str, dry_run: bool, outcome: &Outcome) {
+        let (status, reason) = match *outcome {
+            Outcome::Released => ("released", None),
+            Outcome::Skipped(reason) => ("skipped", Some(reason.to_string())),
+            Outcome::Collected { files, bytes } => {
+                ("collected", Some(format!("deleted {} files ({} bytes)", files, bytes)))
+            }
+            Outcome::DocsPublished => ("docs-published", None),
+            Outcome::Indexed(releases) => {
+                ("indexed", Some(format!("rebuilt history of {} releases", releases)))
+            }
+            Outcome::Failed(ref msg) => ("failed", Some(msg.clone())),
+        };
+        let json = json!({
+            "channel": channel,
+            "rev": self.rev,
+            "previous_version": self.previous_version,
+            "new_version": self.new_version,
+            "dry_run": dry_run,
+            "outcome": status,
+            "reason": reason,
+            "started_at": self.started_at,
+            "duration_secs": secs(self.started),
+            "phases": self.phases.iter().map(|&(name, duration)| {
+                json!({ "name": name, "duration_secs": duration })
+            }).collect::<Vec<_>>(),
+            "artifacts": self.artifacts.iter().map(|a| {
+                json!({ "name": a.name, "size": a.size, "sha256": a.sha256 })
+            }).collect::<Vec<_>>(),
+            "missing_components": self.missing.iter().map(|m| {
+                json!({ "package": m.package, "target": m.target, "required": m.required })
+            }).collect::<Vec<_>>(),
+            "download_bytes": self.download_bytes,
+            "diff": self.diff,
+            "invalidations": self.invalidations.iter().map(|(distribution, id)| {
+                json!({ "distribution": distribution, "id": id })
+            }).collect::<Vec<_>>(),
+            "cleaned": self.cleaned.iter().map(|(path, bytes)| {
+                json!({ "path": path, "bytes": bytes })
+            }).collect::<Vec<_>>(),
+        });
+        match write_json(path, &json) {
+            Ok(()) => println!("wrote report to {}", path.display()),
+            // Don't let a failure to write the report mask how the release
+            // itself went.
+            Err(e) => eprintln!("failed to write {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn secs(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0
+}
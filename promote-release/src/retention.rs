@@ -0,0 +1,301 @@
+//! How long releases are kept in the dated archive, `<upload-dir>/<date>/`,
+//! before `gc` deletes them.
+//!
+//! Configured in the `[dist.retention]` section of the secrets:
+//!
+//! ```toml
+//! [dist.retention]
+//! nightly = 90                # days, channels without a limit are kept forever
+//! beta = 180
+//! pinned = ["nightly-2019-12-16"]
+//! pinned-file = "/data/pinned-toolchains.txt"
+//! ```
+//!
+//! Pinned toolchains, given inline or one per line in `pinned-file`, are kept
+//! no matter how old they are.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Read;
+
+use toml::Value;
+
+use errors::{Error, Result};
+use is_date;
+
+pub struct Policy {
+    channel: String,
+    /// Days to keep the channel's releases for, forever if `None`.
+    days: Option<i64>,
+    /// Dates of the channel's releases which are pinned.
+    pinned: BTreeSet<String>,
+}
+
+impl Policy {
+    pub fn from_secrets(secrets: &Value, channel: &str) -> Result<Policy> {
+        let config = secrets.get("dist").and_then(|d| d.get("retention"));
+        let days = match config.and_then(|c| c.get(channel)) {
+            Some(days) => {
+                let days = days.as_integer()
+                    .filter(|&d| d >= 0)
+                    .ok_or_else(|| bad(&format!("`{}` must be a number of days", channel)))?;
+                Some(days)
+            }
+            None => None,
+        };
+
+        let mut toolchains = Vec::new();
+        if let Some(pinned) = config.and_then(|c| c.get("pinned")) {
+            let pinned = pinned.as_array()
+                .and_then(|a| a.iter().map(|v| v.as_str()).collect::<Option<Vec<_>>>())
+                .ok_or_else(|| bad("`pinned` must be a list of strings"))?;
+            toolchains.extend(pinned.iter().map(|s| s.to_string()));
+        }
+        if let Some(file) = config.and_then(|c| c.get("pinned-file")) {
+            let file = file.as_str().ok_or_else(|| bad("`pinned-file` must be a path"))?;
+            let mut contents = String::new();
+            t!(t!(File::open(file)).read_to_string(&mut contents));
+            toolchains.extend(contents.lines()
+                .map(|l| l.split('#').next().unwrap().trim().to_string())
+                .filter(|l| !l.is_empty()));
+        }
+
+        // Toolchains look like `nightly-2019-12-16`, possibly followed by a
+        // target, and only the ones for this channel matter here.
+        let prefix = format!("{}-", channel);
+        let pinned = toolchains.iter()
+            .filter_map(|t| t.strip_prefix(&prefix[..]))
+            .filter_map(|rest| rest.get(..10))
+            .filter(|date| is_date(date))
+            .map(|date| date.to_string())
+            .collect();
+
+        Ok(Policy { channel: channel.to_string(), days, pinned })
+    }
+
+    /// Returns why the release archived on `date` should be kept as of
+    /// `today`, or `None` if it can be deleted.
+    pub fn keep(&self, date: &str, today: &str) -> Result<Option<String>> {
+        if self.pinned.contains(date) {
+            return Ok(Some("pinned".to_string()))
+        }
+        let days = match self.days {
+            Some(days) => days,
+            None => return Ok(Some(format!("{} is kept forever", self.channel))),
+        };
+        let age = days_between(date, today)?;
+        if age <= days {
+            return Ok(Some(format!("{} days old, kept for {}", age, days)))
+        }
+        Ok(None)
+    }
+}
+
+/// Returns the channel a manifest, or a file that goes with one like its
+/// signature, belongs to, judging by its name.
+pub fn manifest_channel(name: &str) -> Option<&'static str> {
+    let rest = name.strip_prefix("channel-rust-")?;
+    ["nightly", "beta", "stable", "dev"].iter()
+        .cloned()
+        .find(|c| rest.starts_with(c))
+        .or_else(|| {
+            if rest.starts_with(|c: char| c.is_ascii_digit()) { Some("stable") } else { None }
+        })
+}
+
+/// A channel's files among those archived under one date.
+pub struct Archived {
+    /// The channel's manifests and the artifacts they list, along with
+    /// their signatures and hashes.
+    pub files: Vec<String>,
+    /// Those of `files` which other channels' manifests list as well.
+    pub shared: BTreeSet<String>,
+}
+
+/// Picks the files of `channel` out of the `names` archived under one date.
+/// Tarballs can't be told apart by name, as dev releases keep whatever names
+/// CI gave them, so they belong to the channels whose `manifests` archived
+/// alongside list them. Manifests are given by name, with the names of the
+/// artifacts they list.
+pub fn archived(channel: &str, names: &[String], manifests: &[(String, Vec<String>)])
+                -> Archived {
+    let mut ours = BTreeSet::new();
+    let mut theirs = BTreeSet::new();
+    for (name, artifacts) in manifests {
+        let listed = if manifest_channel(name) == Some(channel) { &mut ours } else { &mut theirs };
+        listed.extend(artifacts.iter().map(|a| &a[..]));
+    }
+    let files = names.iter()
+        .filter(|name| {
+            if name.starts_with("channel-rust-") {
+                manifest_channel(name) == Some(channel)
+            } else {
+                ours.contains(artifact(name))
+            }
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let shared = files.iter().filter(|name| theirs.contains(artifact(name))).cloned().collect();
+    Archived { files, shared }
+}
+
+/// The artifact a signature or hash is of.
+fn artifact(name: &str) -> &str {
+    name.trim_end_matches(".asc").trim_end_matches(".sha256")
+}
+
+/// Number of days from one `YYYY-MM-DD` date to another.
+fn days_between(from: &str, to: &str) -> Result<i64> {
+    let parse = |date: &str| {
+        time::strptime(date, "%Y-%m-%d")
+            .map(|tm| tm.to_timespec())
+            .map_err(|e| Error::Config(format!("invalid date `{}`: {}", date, e)))
+    };
+    Ok((parse(to)? - parse(from)?).num_days())
+}
+
+fn bad(msg: &str) -> Error {
+    Error::Config(format!("dist.retention: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use toml::Value;
+
+    use errors::Error;
+    use tests::TempDir;
+    use super::{archived, manifest_channel, Policy};
+
+    fn policy(secrets: &str, channel: &str) -> Policy {
+        Policy::from_secrets(&secrets.parse::<Value>().unwrap(), channel).unwrap()
+    }
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn channels_of_manifests() {
+        let cases = [
+            ("channel-rust-nightly.toml", Some("nightly")),
+            ("channel-rust-beta.toml.asc", Some("beta")),
+            ("channel-rust-stable-date.txt", Some("stable")),
+            ("channel-rust-1.40.toml", Some("stable")),
+            ("channel-rust-1.40.0.toml.sha256", Some("stable")),
+            ("channel-rust-dev.toml", Some("dev")),
+            ("channel-rust-dev-diff.json", Some("dev")),
+            ("channel-rust-.toml", None),
+            ("rust-nightly-x86_64-unknown-linux-gnu.tar.gz", None),
+        ];
+        for &(name, channel) in cases.iter() {
+            assert_eq!(manifest_channel(name), channel, "{}", name);
+        }
+    }
+
+    #[test]
+    fn picks_archived_files_by_manifest() {
+        let names = strings(&[
+            "channel-rust-nightly.toml",
+            "channel-rust-nightly.toml.asc",
+            "channel-rust-dev.toml",
+            "channel-rust-dev.toml.asc",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz.asc",
+            "rust-1.41.0-x86_64-unknown-linux-gnu.tar.gz",
+            "rust-1.41.0-x86_64-unknown-linux-gnu.tar.gz.asc",
+            "rust-1.41.0-x86_64-unknown-linux-gnu.tar.gz.sha256",
+            "unlisted.tar.gz",
+        ]);
+        // Dev released a stable build and the same nightly as nightly.
+        let manifests = vec![
+            ("channel-rust-nightly.toml".to_string(),
+             strings(&["rust-nightly-x86_64-unknown-linux-gnu.tar.gz"])),
+            ("channel-rust-dev.toml".to_string(),
+             strings(&["rust-1.41.0-x86_64-unknown-linux-gnu.tar.gz",
+                       "rust-nightly-x86_64-unknown-linux-gnu.tar.gz"])),
+        ];
+
+        let dev = archived("dev", &names, &manifests);
+        assert_eq!(dev.files, strings(&[
+            "channel-rust-dev.toml",
+            "channel-rust-dev.toml.asc",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz.asc",
+            "rust-1.41.0-x86_64-unknown-linux-gnu.tar.gz",
+            "rust-1.41.0-x86_64-unknown-linux-gnu.tar.gz.asc",
+            "rust-1.41.0-x86_64-unknown-linux-gnu.tar.gz.sha256",
+        ]));
+        assert_eq!(dev.shared.into_iter().collect::<Vec<_>>(), strings(&[
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz.asc",
+        ]));
+
+        let nightly = archived("nightly", &names, &manifests);
+        assert_eq!(nightly.files, strings(&[
+            "channel-rust-nightly.toml",
+            "channel-rust-nightly.toml.asc",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz",
+            "rust-nightly-x86_64-unknown-linux-gnu.tar.gz.asc",
+        ]));
+        assert_eq!(nightly.shared.len(), 2);
+        assert!(archived("beta", &names, &manifests).files.is_empty());
+    }
+
+    #[test]
+    fn keeps_recent_releases() {
+        let policy = policy("[dist.retention]\nnightly = 90", "nightly");
+        assert_eq!(policy.keep("2019-12-16", "2019-12-16").unwrap().as_deref(),
+                   Some("0 days old, kept for 90"));
+        assert_eq!(policy.keep("2019-09-17", "2019-12-16").unwrap().as_deref(),
+                   Some("90 days old, kept for 90"));
+        assert_eq!(policy.keep("2019-09-16", "2019-12-16").unwrap(), None);
+        assert!(policy.keep("2019-13-01", "2019-12-16").is_err());
+    }
+
+    #[test]
+    fn keeps_channels_without_a_limit_forever() {
+        let policy = policy("[dist.retention]\nnightly = 90", "stable");
+        assert_eq!(policy.keep("2015-05-15", "2019-12-16").unwrap().as_deref(),
+                   Some("stable is kept forever"));
+    }
+
+    #[test]
+    fn keeps_pinned_releases() {
+        let dir = TempDir::new("retention");
+        let file = dir.path().join("pinned.txt");
+        fs::write(&file, "# pinned by CI\n\
+                          nightly-2019-01-01-x86_64-unknown-linux-gnu\n\
+                          \n\
+                          beta-2019-02-02 # not nightly\n").unwrap();
+        let secrets = format!("[dist.retention]\n\
+                               nightly = 30\n\
+                               pinned = [\"nightly-2019-03-03\", \"nightly-latest\"]\n\
+                               pinned-file = {:?}\n",
+                              file.display().to_string());
+        let policy = policy(&secrets, "nightly");
+        for date in ["2019-01-01", "2019-03-03"].iter() {
+            assert_eq!(policy.keep(date, "2019-12-16").unwrap().as_deref(), Some("pinned"));
+        }
+        assert_eq!(policy.keep("2019-02-02", "2019-12-16").unwrap(), None);
+        assert_eq!(policy.keep("2019-03-04", "2019-12-16").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        let bad = [
+            "[dist.retention]\nnightly = -1",
+            "[dist.retention]\nnightly = \"90\"",
+            "[dist.retention]\npinned = \"nightly-2019-12-16\"",
+            "[dist.retention]\npinned-file = 1",
+        ];
+        for secrets in bad.iter() {
+            match Policy::from_secrets(&secrets.parse().unwrap(), "nightly") {
+                Err(Error::Config(msg)) => assert!(msg.starts_with("dist.retention: "), "{}", msg),
+                Err(e) => panic!("unexpected error for {}: {}", secrets, e),
+                Ok(_) => panic!("accepted {}", secrets),
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Progress of a release, persisted in the work directory so that a run which
+//! dies halfway through can pick up where it left off.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use errors::Result;
+
+/// The phases of a release in the order they happen. A state file records the
+/// last phase which completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Downloaded,
+    Configured,
+    Signed,
+    SignaturesUploaded,
+    ArchivePublished,
+    DocsPublished,
+    ReleasePublished,
+    Indexed,
+    Invalidated,
+    Verified,
+}
+
+const PHASES: &[Phase] = &[
+    Phase::Downloaded,
+    Phase::Configured,
+    Phase::Signed,
+    Phase::SignaturesUploaded,
+    Phase::ArchivePublished,
+    Phase::DocsPublished,
+    Phase::ReleasePublished,
+    Phase::Indexed,
+    Phase::Invalidated,
+    Phase::Verified,
+];
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Phase::Downloaded => "downloaded",
+            Phase::Configured => "configured",
+            Phase::Signed => "signed",
+            Phase::SignaturesUploaded => "signatures-uploaded",
+            Phase::ArchivePublished => "archive-published",
+            Phase::DocsPublished => "docs-published",
+            Phase::ReleasePublished => "release-published",
+            Phase::Indexed => "indexed",
+            Phase::Invalidated => "invalidated",
+            Phase::Verified => "verified",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Phase> {
+        PHASES.iter().cloned().find(|p| p.name() == name)
+    }
+}
+
+/// How far a previous run got with a release.
+#[derive(Debug, PartialEq)]
+pub struct Progress {
+    /// The last phase which completed.
+    pub phase: Phase,
+    /// The date the release was started on, which it's archived and
+    /// recorded under however long it takes to finish.
+    pub date: String,
+}
+
+/// Location of the state file for `channel` underneath `work`.
+pub fn path(work: &Path, channel: &str) -> PathBuf {
+    work.join(format!("state-{}.json", channel))
+}
+
+/// Returns how far a previous run got with `rev` on `channel`, if it got
+/// anywhere.
+pub fn load(work: &Path, channel: &str, rev: &str) -> Option<Progress> {
+    let mut contents = String::new();
+    File::open(path(work, channel))
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .ok()?;
+    let state: serde_json::Value = serde_json::from_str(&contents).ok()?;
+    if state["channel"] != channel || state["rev"] != rev {
+        return None
+    }
+    Some(Progress {
+        phase: Phase::from_name(state["phase"].as_str()?)?,
+        date: state["date"].as_str()?.to_string(),
+    })
+}
+
+/// Records that `phase` has completed for `rev` on `channel`, in a release
+/// started on `date`.
+pub fn save(work: &Path, channel: &str, rev: &str, date: &str, phase: Phase) -> Result<()> {
+    let state = json!({
+        "channel": channel,
+        "rev": rev,
+        "date": date,
+        "phase": phase.name(),
+    }).to_string();
+
+    // Write to a temporary file first so we never leave a truncated state file
+    // behind if we die while writing it.
+    let dst = path(work, channel);
+    let tmp = dst.with_extension("json.tmp");
+    t!(t!(File::create(&tmp)).write_all(state.as_bytes()));
+    t!(fs::rename(&tmp, &dst));
+    Ok(())
+}
+
+/// Forgets about any progress made on `channel`.
+pub fn clear(work: &Path, channel: &str) -> Result<()> {
+    let path = path(work, channel);
+    if path.exists() {
+        t!(fs::remove_file(&path));
+    }
+    Ok(())
+}
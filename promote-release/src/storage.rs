@@ -0,0 +1,355 @@
+//! Abstraction over where release artifacts are downloaded from and uploaded
+//! to.
+//!
+//! Locations are passed around as strings: anything starting with `s3://` is
+//! a remote `s3://bucket/key` location and everything else is a path on the
+//! local filesystem. Directory locations end with a `/`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use errors::{Error, Result};
+use {file_name, files_in, output, run};
+use s3::S3;
+
+/// An object found when listing a remote prefix.
+pub struct Object {
+    /// Key of the object relative to the prefix that was listed.
+    pub key: String,
+    pub size: u64,
+    /// Changes whenever the object's contents do. This is the ETag on S3, and
+    /// stands in for it with the modification time everywhere else.
+    pub etag: String,
+}
+
+/// Storage is shared between the threads downloading artifacts.
+pub trait Storage: Sync {
+    /// Copies everything under `src` to `dst`, either of which may be local or
+    /// remote. If `cache_control` is specified then the uploaded objects
+    /// get their `Cache-Control` metadata replaced with it.
+    fn copy_recursive(&self, src: &str, dst: &str, cache_control: Option<&str>) -> Result<()>;
+
+    /// Makes the remote prefix `dst` mirror the local directory `src`,
+    /// deleting any objects in `dst` which don't exist in `src`.
+    fn sync_delete(&self, src: &Path, dst: &str) -> Result<()>;
+
+    /// Lists all objects underneath the remote prefix `prefix`, recursively.
+    fn list(&self, prefix: &str) -> Result<Vec<Object>>;
+
+    /// Whether the ETags `list` returns are S3's own, which can be compared
+    /// with `hashes::Digests::etag` to tell whether a file was changed.
+    fn md5_etags(&self) -> bool {
+        false
+    }
+
+    /// Lists the names of the "directories" immediately underneath the remote
+    /// prefix `prefix`, i.e. the distinct next components of keys in it.
+    fn list_dirs(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Uploads the single file `src` to the remote location `dst`.
+    fn put_object(&self, src: &Path, dst: &str) -> Result<()>;
+
+    /// Downloads the single remote object `src` to `dst`, returning whether
+    /// it existed.
+    fn get_object(&self, src: &str, dst: &Path) -> Result<bool>;
+
+    /// Deletes each of `keys`, given relative to the remote prefix `prefix`.
+    fn delete(&self, prefix: &str, keys: Vec<String>) -> Result<()>;
+}
+
+/// Creates the storage backend configured in the `[dist]` section of the
+/// secrets, defaulting to the `aws` CLI.
+pub fn from_secrets(secrets: &::toml::Value) -> Result<Box<dyn Storage>> {
+    let optional = |key| secrets.get("dist").and_then(|d| d.get(key));
+    Ok(match optional("storage").and_then(|s| s.as_str()).unwrap_or("aws") {
+        "s3" => {
+            let region = dist(secrets, "upload-bucket-region")?;
+            let endpoint = optional("s3-endpoint")
+                .and_then(|s| s.as_str())
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+            let parallelism = optional("s3-parallelism")
+                .and_then(|s| s.as_integer())
+                .unwrap_or(8);
+            Box::new(S3::new(dist(secrets, "aws-access-key-id")?,
+                             dist(secrets, "aws-secret-key")?,
+                             region,
+                             &endpoint,
+                             parallelism as usize))
+        }
+        "aws" => Box::new(AwsCli {
+            access_key: dist(secrets, "aws-access-key-id")?.to_string(),
+            secret_key: dist(secrets, "aws-secret-key")?.to_string(),
+        }),
+        "local" => Box::new(Local {
+            root: PathBuf::from(dist(secrets, "storage-root")?),
+        }),
+        s => return Err(Error::Config(format!("unknown storage backend: {}", s))),
+    })
+}
+
+/// Looks up the string `key` in the `[dist]` section of the secrets.
+pub fn dist<'a>(secrets: &'a ::toml::Value, key: &str) -> Result<&'a str> {
+    secrets.get("dist")
+        .and_then(|d| d.get(key))
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| Error::Config(format!("`dist.{}` missing from secrets", key)))
+}
+
+/// Storage which shells out to the `aws` CLI.
+pub struct AwsCli {
+    access_key: String,
+    secret_key: String,
+}
+
+impl AwsCli {
+    fn aws_s3(&self) -> Command {
+        let mut cmd = Command::new("aws");
+        cmd.arg("s3")
+           .env("AWS_ACCESS_KEY_ID", &self.access_key)
+           .env("AWS_SECRET_ACCESS_KEY", &self.secret_key);
+        cmd
+    }
+}
+
+/// Failures of the `aws` CLI are failures to talk to storage.
+fn aws(res: Result<()>) -> Result<()> {
+    res.map_err(|e| match e {
+        Error::Command(msg) => Error::Storage(msg),
+        e => e,
+    })
+}
+
+impl Storage for AwsCli {
+    fn copy_recursive(&self, src: &str, dst: &str, cache_control: Option<&str>) -> Result<()> {
+        let mut cmd = self.aws_s3();
+        cmd.arg("cp")
+           .arg("--recursive")
+           .arg("--only-show-errors");
+        if let Some(cache_control) = cache_control {
+            cmd.arg("--metadata-directive")
+               .arg("REPLACE")
+               .arg("--cache-control")
+               .arg(cache_control);
+        }
+        aws(run(cmd.arg(src).arg(dst)))
+    }
+
+    fn sync_delete(&self, src: &Path, dst: &str) -> Result<()> {
+        aws(run(self.aws_s3()
+                    .arg("sync")
+                    .arg("--delete")
+                    .arg("--only-show-errors")
+                    .arg(format!("{}/", src.display()))
+                    .arg(dst)))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        let (_bucket, key_prefix) = split_url(prefix)?;
+        // `aws s3 ls` exits unsuccessfully if nothing matches the prefix, so
+        // treat failure as an empty listing.
+        let out = self.aws_s3()
+            .arg("ls")
+            .arg("--recursive")
+            .arg(prefix)
+            .output()
+            .map_err(|e| Error::Storage(format!("failed to run aws: {}", e)))?;
+        if !out.status.success() {
+            return Ok(Vec::new())
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).lines().filter_map(|line| {
+            // Lines look like `2019-12-16 12:34:56       1234 dist/foo.tar.xz`
+            let mut rest = line.trim_start();
+            for _ in 0..2 {
+                rest = rest[rest.find(' ')?..].trim_start();
+            }
+            let modified = line[..line.len() - rest.len()].trim();
+            let size_end = rest.find(' ')?;
+            let size = rest[..size_end].parse().ok()?;
+            let key = rest[size_end..].trim_start();
+            if !key.starts_with(key_prefix) {
+                return None
+            }
+            Some(Object {
+                key: key[key_prefix.len()..].to_string(),
+                size,
+                etag: modified.to_string(),
+            })
+        }).collect())
+    }
+
+    fn list_dirs(&self, prefix: &str) -> Result<Vec<String>> {
+        let out = self.aws_s3()
+            .arg("ls")
+            .arg(prefix)
+            .output()
+            .map_err(|e| Error::Storage(format!("failed to run aws: {}", e)))?;
+        if !out.status.success() {
+            return Ok(Vec::new())
+        }
+        // Directories are listed as `                           PRE 2019-12-16/`
+        Ok(String::from_utf8_lossy(&out.stdout).lines().filter_map(|line| {
+            let dir = line.trim_start().strip_prefix("PRE ")?;
+            Some(dir.trim().trim_end_matches('/').to_string())
+        }).collect())
+    }
+
+    fn put_object(&self, src: &Path, dst: &str) -> Result<()> {
+        aws(output(self.aws_s3()
+                       .arg("cp")
+                       .arg("--only-show-errors")
+                       .arg(src)
+                       .arg(dst)).map(|_| ()))
+    }
+
+    fn get_object(&self, src: &str, dst: &Path) -> Result<bool> {
+        if !self.list(src)?.iter().any(|object| object.key.is_empty()) {
+            return Ok(false)
+        }
+        aws(run(self.aws_s3()
+                    .arg("cp")
+                    .arg("--only-show-errors")
+                    .arg(src)
+                    .arg(dst)))?;
+        Ok(true)
+    }
+
+    fn delete(&self, prefix: &str, keys: Vec<String>) -> Result<()> {
+        for key in keys {
+            aws(run(self.aws_s3()
+                        .arg("rm")
+                        .arg("--only-show-errors")
+                        .arg(format!("{}{}", prefix, key))))?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage which maps `s3://bucket/key` to `root/bucket/key` on the local
+/// filesystem, used to run promotions against a directory tree.
+pub struct Local {
+    root: PathBuf,
+}
+
+impl Local {
+    fn resolve(&self, location: &str) -> PathBuf {
+        match location.strip_prefix("s3://") {
+            Some(rest) => self.root.join(rest),
+            None => PathBuf::from(location),
+        }
+    }
+}
+
+impl Storage for Local {
+    fn copy_recursive(&self, src: &str, dst: &str, _cache_control: Option<&str>) -> Result<()> {
+        let src = self.resolve(src);
+        if !src.is_dir() {
+            return Ok(())
+        }
+        for file in files_in(&src)? {
+            let key = key_for(file.strip_prefix(&src).unwrap());
+            self.put_object(&file, &format!("{}{}", dst, key))?;
+        }
+        Ok(())
+    }
+
+    fn sync_delete(&self, src: &Path, dst: &str) -> Result<()> {
+        self.copy_recursive(&src.display().to_string(), dst, None)?;
+        let dst = self.resolve(dst);
+        if !dst.is_dir() {
+            return Ok(())
+        }
+        for file in files_in(&dst)? {
+            if !src.join(file.strip_prefix(&dst).unwrap()).exists() {
+                t!(fs::remove_file(&file));
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<Object>> {
+        // Like S3, the prefix doesn't have to end at a directory.
+        let split = prefix.rfind('/').map_or(0, |i| i + 1);
+        let (dir, start) = (self.resolve(&prefix[..split]), &prefix[split..]);
+        if !dir.is_dir() {
+            return Ok(Vec::new())
+        }
+        let mut objects = Vec::new();
+        for file in files_in(&dir)? {
+            let key = key_for(file.strip_prefix(&dir).unwrap());
+            if let Some(key) = key.strip_prefix(start) {
+                let metadata = t!(file.metadata());
+                let modified = t!(metadata.modified())
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                objects.push(Object {
+                    key: key.to_string(),
+                    size: metadata.len(),
+                    etag: format!("{}.{:09}", modified.as_secs(), modified.subsec_nanos()),
+                });
+            }
+        }
+        Ok(objects)
+    }
+
+    fn list_dirs(&self, prefix: &str) -> Result<Vec<String>> {
+        let dir = self.resolve(prefix);
+        if !dir.is_dir() {
+            return Ok(Vec::new())
+        }
+        let mut dirs = Vec::new();
+        for entry in t!(dir.read_dir()) {
+            let path = t!(entry).path();
+            if path.is_dir() {
+                dirs.push(file_name(&path)?.to_string());
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    fn put_object(&self, src: &Path, dst: &str) -> Result<()> {
+        let dst = self.resolve(dst);
+        t!(fs::create_dir_all(dst.parent().unwrap()));
+        t!(fs::copy(src, &dst));
+        Ok(())
+    }
+
+    fn get_object(&self, src: &str, dst: &Path) -> Result<bool> {
+        let src = self.resolve(src);
+        if !src.is_file() {
+            return Ok(false)
+        }
+        t!(fs::copy(&src, dst));
+        Ok(true)
+    }
+
+    fn delete(&self, prefix: &str, keys: Vec<String>) -> Result<()> {
+        let dir = self.resolve(prefix);
+        for key in keys {
+            t!(fs::remove_file(dir.join(&key)));
+        }
+        Ok(())
+    }
+}
+
+/// Splits `s3://bucket/key` into `bucket` and `key`.
+pub fn split_url(url: &str) -> Result<(&str, &str)> {
+    let rest = url.strip_prefix("s3://")
+        .ok_or_else(|| Error::Internal(format!("not an s3 url: {}", url)))?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    })
+}
+
+/// Converts a relative path into an object key, always using `/` as the
+/// separator.
+pub fn key_for(relative: &Path) -> String {
+    relative.iter()
+        .map(|s| s.to_str().unwrap())
+        .collect::<Vec<_>>()
+        .join("/")
+}
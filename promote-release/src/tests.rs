@@ -0,0 +1,792 @@
+//! Helpers shared by the tests of several modules: scratch directories, a
+//! tiny HTTP server to stand in for the services we talk to, and whole
+//! releases run against local storage.
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde_json;
+use xz2::write::XzEncoder;
+
+use errors::Error;
+use pgp::PublicKey;
+use report::Outcome;
+use state::Phase;
+use s3::xml_tags;
+use {files_in, hashes, manifest_artifacts, read_manifest, rust_version, state, url_file_name};
+use {Context, Task};
+
+/// A directory which is removed again once the test is done with it.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!("promote-release-{}-{}-{}",
+                                               name,
+                                               process::id(),
+                                               NEXT.fetch_add(1, Ordering::SeqCst)));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        drop(fs::remove_dir_all(&self.0));
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    /// The path and query of the request.
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+/// An HTTP server answering requests on a background thread, which lives
+/// until the tests exit.
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl Server {
+    /// The `method path` of every request handled so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Starts a server answering every request with the status code and body
+/// returned by `handler`.
+pub fn serve<F>(handler: F) -> Server
+    where F: Fn(&Request) -> (u32, Vec<u8>) + Send + 'static
+{
+    serve_with_headers(move |request| {
+        let (code, body) = handler(request);
+        (code, Vec::new(), body)
+    })
+}
+
+/// Like `serve`, with `handler` returning response headers too. Responses to
+/// `HEAD` requests only get the headers, where `Content-Length` may be given
+/// explicitly.
+pub fn serve_with_headers<F>(handler: F) -> Server
+    where F: Fn(&Request) -> (u32, Vec<(String, String)>, Vec<u8>) + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let log = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap_or("").to_string();
+            let path = parts.next().unwrap_or("").to_string();
+            let mut len = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim();
+                if header.is_empty() {
+                    break
+                }
+                let lower = header.to_lowercase();
+                if let Some(value) = lower.strip_prefix("content-length:") {
+                    len = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+
+            log.lock().unwrap().push(format!("{} {}", method, path));
+            let head_only = method == "HEAD";
+            let (code, mut headers, response) = handler(&Request { method, path, body });
+            if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("Content-Length")) {
+                headers.push(("Content-Length".to_string(), response.len().to_string()));
+            }
+            let mut head = format!("HTTP/1.1 {} Mock\r\nConnection: close\r\n", code);
+            for (k, v) in headers {
+                head.push_str(&format!("{}: {}\r\n", k, v));
+            }
+            head.push_str("\r\n");
+            let response = if head_only { &[][..] } else { &response[..] };
+            drop(stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(response)));
+        }
+    });
+    Server { url, requests }
+}
+
+
+/// Writes a package tarball to `path` the way CI builds them, with just a
+/// `version` file inside the top-level directory `top`.
+pub fn tarball(path: &Path, top: &str, version: &str) {
+    tarball_with(path, top, version, &[]);
+}
+
+/// Like `tarball`, with `files` given as `(path, contents)` relative to `top`
+/// added to it.
+pub fn tarball_with(path: &Path, top: &str, version: &str, files: &[(&str, &str)]) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let file = fs::File::create(path).unwrap();
+    let mut builder = ::tar::Builder::new(GzEncoder::new(file, Compression::fast()));
+    let version = format!("{}\n", version);
+    let files = Some(("version", &version[..])).into_iter().chain(files.iter().cloned());
+    for (name, contents) in files {
+        let mut header = ::tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, format!("{}/{}", top, name), contents.as_bytes())
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap();
+}
+
+/// Replaces the tarball `gz` by an xz-compressed one, like CI uploads for
+/// most packages nowadays.
+pub fn xz(gz: &Path) {
+    let mut tar = Vec::new();
+    GzDecoder::new(fs::File::open(gz).unwrap()).read_to_end(&mut tar).unwrap();
+    let xz = fs::File::create(gz.with_extension("xz")).unwrap();
+    let mut encoder = XzEncoder::new(xz, 6);
+    encoder.write_all(&tar).unwrap();
+    encoder.finish().unwrap();
+    fs::remove_file(gz).unwrap();
+}
+
+/// Serves the files underneath `root`, like the CDN in front of a bucket.
+pub fn serve_dir(root: &Path) -> Server {
+    let root = root.to_path_buf();
+    serve(move |request| {
+        match fs::read(root.join(request.path.trim_start_matches('/'))) {
+            Ok(contents) => (200, contents),
+            Err(_) => (404, Vec::new()),
+        }
+    })
+}
+
+/// A mock of the CloudFront API whose invalidations are immediately complete,
+/// or never if `complete` is false. Invalidation ids are `I<n>-<paths>`.
+pub fn cloudfront(complete: bool) -> Server {
+    let next = AtomicUsize::new(0);
+    serve(move |request| {
+        let body = if request.method == "POST" && request.path.ends_with("/invalidation") {
+            let id = next.fetch_add(1, Ordering::SeqCst);
+            let body = String::from_utf8_lossy(&request.body);
+            let count = xml_tags(&body, "Path").len();
+            format!("<Invalidation><Id>I{}-{}</Id></Invalidation>", id, count)
+        } else if complete {
+            "<Invalidation><Status>Completed</Status></Invalidation>".to_string()
+        } else {
+            "<Invalidation><Status>InProgress</Status></Invalidation>".to_string()
+        };
+        (201, body.into_bytes())
+    })
+}
+
+/// The date releases are made on in the tests.
+pub const DATE: &str = "2019-12-16";
+
+pub const HOST: &str = "x86_64-unknown-linux-gnu";
+
+/// Everything a release talks to, set up in a scratch directory: local
+/// storage, the CDN serving its bucket and a mock of CloudFront.
+pub struct Env {
+    dir: TempDir,
+    pub cdn: Server,
+    pub cloudfront: Server,
+}
+
+impl Env {
+    pub fn new(name: &str) -> Env {
+        let dir = TempDir::new(name);
+        let cdn = serve_dir(&dir.path().join("storage/static"));
+        write_file(&dir.path().join("password"), "correct horse battery staple\n");
+        Env { dir, cdn, cloudfront: cloudfront(true) }
+    }
+
+    /// The bucket releases are published to.
+    pub fn bucket(&self) -> PathBuf {
+        self.dir.path().join("storage/static")
+    }
+
+    pub fn work(&self) -> PathBuf {
+        self.dir.path().join("work")
+    }
+
+    pub fn secrets(&self) -> String {
+        let root = self.dir.path();
+        format!(r#"
+            [dist]
+            storage = "local"
+            storage-root = {:?}
+            build-manifest = "native"
+            upload-bucket = "static"
+            upload-dir = "dist"
+            upload-addr = "{}"
+            gpg-key = {:?}
+            gpg-password-file = {:?}
+            aws-access-key-id = "key"
+            aws-secret-key = "secret"
+            cloudfront-distribution-id = "DIST"
+            rustdoc-cf-distribution-id = "DOCS"
+            cloudfront-endpoint = "{}"
+            min-free-gb = 0
+            recompress-parallelism = 1
+            s3-parallelism = 2
+            verify-sample = 2
+        "#,
+            root.join("storage").display().to_string(),
+            self.cdn.url,
+            data("secret-key.asc").display().to_string(),
+            root.join("password").display().to_string(),
+            self.cloudfront.url)
+    }
+
+    pub fn context(&self, task: Task, channel: &str, arg: Option<&str>) -> Context {
+        self.context_with(task, channel, arg, "")
+    }
+
+    /// A context whose secrets have `extra` appended to the usual ones.
+    pub fn context_with(&self, task: Task, channel: &str, arg: Option<&str>, extra: &str)
+                        -> Context {
+        Context::new(task,
+                     self.work(),
+                     channel.to_string(),
+                     (self.secrets() + extra).parse().unwrap(),
+                     arg.map(|a| a.to_string()),
+                     DATE.to_string()).unwrap()
+    }
+
+    /// Makes `version` the live release of `channel`, as far as the checks
+    /// against the previous release go.
+    pub fn live(&self, channel: &str, version: &str) {
+        write_file(&self.bucket().join(format!("dist/channel-rust-{}.toml", channel)),
+              &format!("manifest-version = \"2\"\n\
+                        date = \"2019-12-01\"\n\
+                        [pkg.rust]\n\
+                        version = \"{}\"\n", version));
+    }
+
+    /// Uploads the artifacts CI builds of `version` for `rev`, whose tarball
+    /// names contain `name` in place of the version.
+    pub fn ci(&self, rev: &str, name: &str, version: &str) {
+        let dir = self.dir.path().join("storage/rust-lang-ci2/rustc-builds").join(rev);
+        for pkg in ["rust", "rustc", "rust-std", "cargo", "rust-docs"].iter() {
+            let top = format!("{}-{}-{}", pkg, name, HOST);
+            let files: &[(&str, &str)] = if *pkg == "rust-docs" {
+                &[("rust-docs/share/doc/rust/html/index.html", "<h1>The Rust docs</h1>"),
+                  ("rust-docs/share/doc/rust/html/std/index.html", "<h1>std</h1>")]
+            } else {
+                &[]
+            };
+            let path = dir.join(format!("{}.tar.gz", top));
+            tarball_with(&path, &top, version, files);
+            if *pkg == "cargo" {
+                xz(&path);
+            }
+        }
+        let top = format!("rust-src-{}", name);
+        tarball(&dir.join(format!("{}.tar.gz", top)), &top, version);
+    }
+}
+
+/// Writes `contents` to `path`, creating the directories leading up to it.
+pub fn write_file(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// A file from `tests/data`.
+pub fn data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data").join(name)
+}
+
+const REV: &str = "0d2817a43f72d0ba0e1ab11a89ba7a4c5e42b7b9";
+const VERSION: &str = "1.42.0-nightly (0d2817a43 2019-12-16)";
+
+#[test]
+fn publishes_through_local_storage() {
+    let env = Env::new("publish");
+    env.live("nightly", "1.42.0-nightly (c9290dcee 2019-12-15)");
+    env.ci(REV, "nightly", VERSION);
+    match env.context(Task::Release, "nightly", Some(REV)).run() {
+        Ok(Outcome::Released) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // The live manifest is the new one, and everything it lists was archived
+    // and signed along with it.
+    let dist = env.bucket().join("dist");
+    let manifest = read_manifest(&dist.join("channel-rust-nightly.toml")).unwrap();
+    assert_eq!(rust_version(&manifest).unwrap().to_string(), VERSION);
+    assert!(dist.join("channel-rust-nightly.toml.asc").exists());
+    let key = PublicKey::load(&env.dir.path().join("gnupg"), &data("public-key.asc")).unwrap();
+    let artifacts = manifest_artifacts(&manifest);
+    // Cargo was only uploaded as xz and has been recompressed.
+    assert_eq!(artifacts.len(), 7);
+    for (url, hash) in artifacts {
+        let name = url_file_name(&url).unwrap();
+        assert_eq!(url, format!("{}/dist/{}/{}", env.cdn.url, DATE, name));
+        let archived = dist.join(DATE).join(name);
+        assert_eq!(hashes::digests(&archived).unwrap().sha256, hash);
+        let signature = fs::read_to_string(dist.join(DATE).join(format!("{}.asc", name)));
+        key.verify(&archived, &signature.unwrap()).unwrap();
+        assert!(dist.join(name).exists());
+    }
+
+    // The diff against the previous release is published next to the
+    // manifest, but wasn't part of what was signed.
+    for dir in [dist.clone(), dist.join(DATE)].iter() {
+        let diff = fs::read_to_string(dir.join("channel-rust-nightly-diff.json")).unwrap();
+        assert!(diff.contains("c9290dcee"), "{}", diff);
+    }
+    assert!(!dist.join(DATE).join("channel-rust-nightly-diff.json.asc").exists());
+
+    // The docs, the history and the signatures for CI were published too.
+    assert!(env.bucket().join("doc/nightly/std/index.html").exists());
+    let history = fs::read_to_string(dist.join("channel-history-nightly.json")).unwrap();
+    assert!(history.contains(REV));
+    let ci = env.dir.path().join("storage/rust-lang-ci2/rustc-builds").join(REV);
+    assert!(ci.join("channel-rust-nightly.toml.asc").exists());
+
+    // Both distributions were invalidated, and there's nothing left over.
+    let requests = env.cloudfront.requests();
+    for distribution in ["DIST", "DOCS"].iter() {
+        let create = format!("POST /2020-05-31/distribution/{}/invalidation", distribution);
+        assert!(requests.contains(&create), "{:?}", requests);
+    }
+    assert!(!state::path(&env.work(), "nightly").exists());
+    assert!(!env.work().join("nightly/dl").exists());
+    assert!(!env.work().join("nightly/diff").exists());
+    let report = fs::read_to_string(env.work().join("report-nightly.json")).unwrap();
+    let report = serde_json::from_str::<serde_json::Value>(&report).unwrap();
+    assert_eq!(report["outcome"], "released");
+    assert_eq!(report["new_version"], VERSION);
+
+    // Trying again finds the rev live already.
+    match env.context(Task::Release, "nightly", Some(REV)).run() {
+        Ok(Outcome::Skipped(reason)) => assert_eq!(reason, "found rev in previous version"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn dry_runs_publish_nothing() {
+    let env = Env::new("dry-run");
+    env.live("nightly", "1.42.0-nightly (c9290dcee 2019-12-15)");
+    env.ci(REV, "nightly", VERSION);
+    let storage = env.dir.path().join("storage");
+    let snapshot = || {
+        files_in(&storage).unwrap().into_iter()
+            .map(|file| (fs::read(&file).unwrap(), file))
+            .collect::<Vec<_>>()
+    };
+    let before = snapshot();
+
+    let mut cx = env.context(Task::Release, "nightly", Some(REV));
+    cx.dry_run = true;
+    cx.stop_after = Some(Phase::Signed);
+    match cx.run() {
+        Err(Error::Network(ref msg)) if msg.starts_with("stopped") => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(!state::path(&env.work(), "nightly").exists());
+
+    let mut cx = env.context(Task::Release, "nightly", Some(REV));
+    cx.dry_run = true;
+    match cx.run() {
+        Ok(Outcome::Released) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(snapshot() == before, "storage was written to");
+    assert_eq!(env.cloudfront.requests(), Vec::<String>::new());
+    assert!(!state::path(&env.work(), "nightly").exists());
+    let report = fs::read_to_string(env.work().join("report-nightly.json")).unwrap();
+    let report = serde_json::from_str::<serde_json::Value>(&report).unwrap();
+    assert_eq!(report["dry_run"], true);
+}
+
+#[test]
+fn rolls_back_only_its_own_channel() {
+    let env = Env::new("rollback");
+    env.live("nightly", "1.42.0-nightly (c9290dcee 2019-12-15)");
+    env.ci(REV, "nightly", VERSION);
+    let beta_rev = "b5b0a2c7b8d1e1e0f1c0d3e0f0a9b8c7d6e5f4a3";
+    env.live("beta", "1.41.0-beta.4 (1f2e3d4c5 2019-12-09)");
+    env.ci(beta_rev, "beta", "1.41.0-beta.5 (b5b0a2c7b 2019-12-16)");
+    for &(channel, rev) in [("nightly", REV), ("beta", beta_rev)].iter() {
+        match env.context(Task::Release, channel, Some(rev)).run() {
+            Ok(Outcome::Released) => {}
+            res => panic!("unexpected result releasing {}: {:?}", channel, res),
+        }
+    }
+
+    // Both channels move on, with beta's new release being live when nightly
+    // is rolled back to the release archived on the same date as beta's.
+    let dist = env.bucket().join("dist");
+    env.live("nightly", "1.42.0-nightly (8a5c2ce31 2019-12-17)");
+    env.live("beta", "1.41.0-beta.6 (77d8f7b5a 2019-12-17)");
+    let beta_tarball = dist.join(format!("rust-beta-{}.tar.gz", HOST));
+    write_file(&beta_tarball, "beta.6");
+    let mut cx = Context::new(Task::Rollback,
+                              env.work(),
+                              "nightly".to_string(),
+                              env.secrets().parse().unwrap(),
+                              Some(DATE.to_string()),
+                              "2019-12-18".to_string()).unwrap();
+    match cx.run() {
+        Ok(Outcome::Released) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let nightly = read_manifest(&dist.join("channel-rust-nightly.toml")).unwrap();
+    assert_eq!(rust_version(&nightly).unwrap().to_string(), VERSION);
+    let beta = read_manifest(&dist.join("channel-rust-beta.toml")).unwrap();
+    assert_eq!(rust_version(&beta).unwrap().to_string(), "1.41.0-beta.6 (77d8f7b5a 2019-12-17)");
+    assert_eq!(fs::read_to_string(&beta_tarball).unwrap(), "beta.6");
+
+    // The rollback is in the history as a release of the archived manifest.
+    let history = fs::read_to_string(dist.join("channel-history-nightly.json")).unwrap();
+    let history = serde_json::from_str::<serde_json::Value>(&history).unwrap();
+    let releases = history["releases"].as_array().unwrap();
+    assert_eq!(releases.len(), 2);
+    assert_eq!(releases[1]["date"], "2019-12-18");
+    assert_eq!(releases[1]["rev"], &REV[..9]);
+    assert_eq!(releases[1]["manifest_url"],
+               format!("{}/dist/{}/channel-rust-nightly.toml", env.cdn.url, DATE));
+}
+
+#[test]
+fn lists_the_channels_archives() {
+    let env = Env::new("archives");
+    let dist = env.bucket().join("dist");
+    for &(date, channel) in [("2019-12-01", "nightly"),
+                             ("2019-12-02", "beta"),
+                             ("2019-12-03", "beta"),
+                             ("2019-12-03", "nightly")].iter() {
+        write_file(&dist.join(date).join(format!("channel-rust-{}.toml", channel)), "");
+    }
+    // Neither a signature on its own nor a directory that isn't a date count.
+    write_file(&dist.join("2019-12-04/channel-rust-nightly.toml.asc"), "");
+    write_file(&dist.join("staging/channel-rust-nightly.toml"), "");
+
+    let cx = env.context(Task::Rollback, "nightly", None);
+    assert_eq!(cx.archives("s3://static/dist/").unwrap(), ["2019-12-01", "2019-12-03"]);
+    let cx = env.context(Task::Rollback, "beta", None);
+    assert_eq!(cx.archives("s3://static/dist/").unwrap(), ["2019-12-02", "2019-12-03"]);
+}
+
+#[test]
+fn releases_dev_builds_apart_from_production() {
+    let env = Env::new("dev");
+    env.live("nightly", "1.42.0-nightly (c9290dcee 2019-12-15)");
+    env.ci(REV, "nightly", VERSION);
+    let dev = "[dist.dev]\n\
+               upload-dir = \"dev-dist\"\n\
+               cloudfront-distribution-id = \"DEVDIST\"\n";
+    match Context::new(Task::Release, env.work(), "dev".to_string(),
+                       (env.secrets() + dev).parse().unwrap(), None, DATE.to_string()) {
+        Err(Error::Config(ref msg)) if msg.contains("need a rev") => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("released dev without a rev"),
+    }
+    // There's no previous dev release to compare against.
+    match env.context_with(Task::Release, "dev", Some(REV), dev).run() {
+        Ok(Outcome::Released) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let dist = env.bucket().join("dev-dist");
+    let manifest = read_manifest(&dist.join("channel-rust-dev.toml")).unwrap();
+    assert_eq!(rust_version(&manifest).unwrap().to_string(), VERSION);
+    for (url, _) in manifest_artifacts(&manifest) {
+        let name = url_file_name(&url).unwrap();
+        assert_eq!(url, format!("{}/dev-dist/{}/{}", env.cdn.url, DATE, name));
+        assert!(dist.join(DATE).join(name).exists());
+    }
+
+    // Nothing of production was touched: not the live nightly, the archive,
+    // the docs or the production distributions.
+    let nightly = read_manifest(&env.bucket().join("dist/channel-rust-nightly.toml")).unwrap();
+    assert_eq!(rust_version(&nightly).unwrap().to_string(),
+               "1.42.0-nightly (c9290dcee 2019-12-15)");
+    assert!(!env.bucket().join("dist").join(DATE).exists());
+    assert!(!env.bucket().join("doc").exists());
+    let requests = env.cloudfront.requests();
+    assert!(requests.contains(&"POST /2020-05-31/distribution/DEVDIST/invalidation".to_string()));
+    assert!(requests.iter().all(|r| r.contains("/distribution/DEVDIST/")), "{:?}", requests);
+}
+
+#[test]
+fn rolls_back_dev_releases_of_other_channels_builds() {
+    // Dev releases keep the names CI gave the tarballs, which are those of
+    // the channel the build was made for.
+    let env = Env::new("rollback-dev");
+    env.ci(REV, "nightly", VERSION);
+    let dev = "[dist.dev]\nupload-dir = \"dev-dist\"\n";
+    match env.context_with(Task::Release, "dev", Some(REV), dev).run() {
+        Ok(Outcome::Released) => {}
+        res => panic!("unexpected result releasing: {:?}", res),
+    }
+
+    let dist = env.bucket().join("dev-dist");
+    let tarball = dist.join(format!("rust-nightly-{}.tar.gz", HOST));
+    write_file(&tarball, "newer");
+    match env.context_with(Task::Rollback, "dev", Some(DATE), dev).run() {
+        Ok(Outcome::Released) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    let manifest = read_manifest(&dist.join("channel-rust-dev.toml")).unwrap();
+    assert_eq!(rust_version(&manifest).unwrap().to_string(), VERSION);
+    assert_eq!(fs::read(&tarball).unwrap(),
+               fs::read(dist.join(DATE).join(format!("rust-nightly-{}.tar.gz", HOST))).unwrap());
+}
+
+#[test]
+fn gc_keeps_what_live_manifests_point_into() {
+    let env = Env::new("gc");
+    let dist = env.bucket().join("dist");
+    let tarball = |date: &str, name: &str| {
+        dist.join(date).join(format!("rust-{}-{}.tar.gz", name, HOST))
+    };
+    let manifest = |path: &Path, date: &str, name: &str| {
+        let url = format!("{}/dist/{}/rust-{}-{}.tar.gz", env.cdn.url, date, name, HOST);
+        write_file(path,
+                   &format!("manifest-version = \"2\"\n\
+                             date = \"{}\"\n\
+                             [pkg.rust]\n\
+                             version = \"1.40.0-{} (22bc9e1d9 {})\"\n\
+                             [pkg.rust.target.{}]\n\
+                             available = true\n\
+                             url = \"{}\"\n\
+                             hash = \"00\"\n", date, name, date, HOST, url));
+    };
+    for date in ["2019-10-01", "2019-11-01"].iter() {
+        for channel in ["nightly", "beta"].iter() {
+            write_file(&tarball(date, channel), date);
+            manifest(&dist.join(date).join(format!("channel-rust-{}.toml", channel)),
+                     date, channel);
+        }
+    }
+    // A stable build released to dev the same day, whose tarballs are named
+    // like stable's rather than dev's.
+    let dev_manifest = dist.join("2019-11-01/channel-rust-dev.toml");
+    manifest(&dev_manifest, "2019-11-01", "1.40.0");
+    write_file(&dev_manifest.with_extension("toml.asc"), "signature");
+    write_file(&tarball("2019-11-01", "1.40.0"), "2019-11-01");
+    write_file(&tarball("2019-11-01", "1.40.0").with_extension("gz.asc"), "signature");
+
+    // Nightly hasn't been released since October, so that's still live even
+    // though it's past the retention period.
+    manifest(&dist.join("channel-rust-nightly.toml"), "2019-10-01", "nightly");
+    let retention = "[dist.retention]\nnightly = 30\ndev = 30\n";
+    match env.context_with(Task::Gc, "nightly", None, retention).run() {
+        Ok(Outcome::Collected { files: 2, .. }) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(tarball("2019-10-01", "nightly").exists());
+    assert!(!tarball("2019-11-01", "nightly").exists());
+    assert!(tarball("2019-11-01", "beta").exists());
+    assert!(tarball("2019-11-01", "1.40.0").exists());
+
+    match env.context_with(Task::Gc, "dev", None, retention).run() {
+        Ok(Outcome::Collected { files: 4, .. }) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(!dev_manifest.exists());
+    assert!(!tarball("2019-11-01", "1.40.0").exists());
+    assert!(tarball("2019-11-01", "beta").exists());
+    assert!(dist.join("2019-11-01/channel-rust-beta.toml").exists());
+}
+
+#[test]
+fn waits_for_the_repo_lock() {
+    let env = Env::new("lock");
+    let cx = env.context_with(Task::Release, "nightly", None, "repo-lock-timeout = 0\n");
+    let held = cx.lock("rust").unwrap();
+    match cx.wait_for_lock("rust") {
+        Err(Error::Locked) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // Whoever holds the lock finishing within the timeout lets us carry on.
+    let cx = env.context_with(Task::Release, "beta", None, "repo-lock-timeout = 60\n");
+    let other = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(held);
+    });
+    cx.wait_for_lock("rust").unwrap();
+    other.join().unwrap();
+}
+
+#[test]
+fn preflight_stops_releases_which_would_fail() {
+    let env = Env::new("preflight");
+    let not_ready = |res: Result<bool, Error>, expected: &str| match res {
+        Err(Error::NotReady(ref msg)) if msg.contains(expected) => {}
+        res => panic!("unexpected result: {:?}", res),
+    };
+
+    // CI hasn't uploaded anything for the rev yet.
+    let mut cx = env.context(Task::Release, "nightly", Some(REV));
+    not_ready(cx.preflight(REV), "doesn't have any artifacts");
+
+    // The rev was built for another channel than the one being released.
+    env.ci(REV, "beta", "1.41.0-beta.5 (eb3f7c2d3 2019-12-13)");
+    not_ready(cx.preflight(REV), "channels are being switched");
+    let mut cx = env.context(Task::Release, "stable", Some(REV));
+    not_ready(cx.preflight(REV), "channels are being switched");
+
+    // Required components are missing, and all of them are reported.
+    let rules = format!("[[dist.components.rules]]\n\
+                         targets = [\"{}\", \"aarch64-unknown-linux-gnu\"]\n\
+                         required = [\"rustc\", \"clippy-preview\"]\n\
+                         warn = [\"rustfmt-preview\"]\n", HOST);
+    let mut cx = env.context_with(Task::Release, "beta", Some(REV), &rules);
+    match cx.preflight(REV) {
+        Err(Error::MissingComponents(missing)) => {
+            assert_eq!(missing, ["clippy-preview (aarch64-unknown-linux-gnu)".to_string(),
+                                 "rustc (aarch64-unknown-linux-gnu)".to_string(),
+                                 format!("clippy-preview ({})", HOST)]);
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+    // What's only warned about is in the report.
+    let warned = cx.report.missing.iter().filter(|m| !m.required).count();
+    assert_eq!(warned, 2);
+
+    // Nothing was downloaded to find any of that out.
+    assert!(!env.work().join("beta/dl").exists());
+    assert!(!env.work().join("nightly/dl").exists());
+    assert!(env.context(Task::Release, "beta", Some(REV)).preflight(REV).unwrap());
+}
+
+#[test]
+fn asks_github_for_the_branch_head() {
+    let github = serve(|request| match &request.path[..] {
+        "/repos/rust-lang/rust/git/ref/heads/master" => {
+            (200, format!("{{\"ref\": \"refs/heads/master\", \
+                           \"object\": {{\"type\": \"commit\", \"sha\": \"{}\"}}}}",
+                          REV).into_bytes())
+        }
+        "/repos/rust-lang/rust/git/ref/heads/beta" => (200, b"<html>".to_vec()),
+        _ => (404, b"{\"message\": \"Not Found\"}".to_vec()),
+    });
+    let env = Env::new("branch-head");
+    let mut cx = env.context_with(Task::Release, "nightly", None,
+                                  &format!("github-api-url = \"{}/\"\n", github.url));
+    assert_eq!(cx.branch_head("master").unwrap(), REV);
+    match cx.branch_head("beta") {
+        Err(Error::Network(ref msg)) if msg.contains("invalid JSON") => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match cx.branch_head("stable") {
+        Err(Error::Network(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(github.requests(),
+               ["GET /repos/rust-lang/rust/git/ref/heads/master",
+                "GET /repos/rust-lang/rust/git/ref/heads/beta",
+                "GET /repos/rust-lang/rust/git/ref/heads/stable"]);
+}
+
+#[test]
+fn exits_with_the_code_of_the_failure() {
+    let exit_code = |res: Result<Outcome, Error>| res.unwrap_err().exit_code();
+    let env = Env::new("exit-codes");
+    env.live("nightly", "1.42.0-nightly (c9290dcee 2019-12-15)");
+    env.ci(REV, "nightly", VERSION);
+
+    assert_eq!(exit_code(env.context(Task::Release, "weekly", None).run()), 2);
+
+    let held = env.context(Task::Release, "nightly", Some(REV)).lock("nightly").unwrap();
+    assert_eq!(exit_code(env.context(Task::Release, "nightly", Some(REV)).run()), 3);
+    drop(held);
+
+    // Resuming a signed release against storage which rejects every upload.
+    let mut cx = env.context(Task::Release, "nightly", Some(REV));
+    cx.stop_after = Some(Phase::Signed);
+    assert!(cx.run().is_err());
+    let s3 = serve(|_| (403, b"<Error><Code>AccessDenied</Code></Error>".to_vec()));
+    let secrets = env.secrets().replace("storage = \"local\"",
+                                        &format!("storage = \"s3\"\n\
+                                                  s3-endpoint = \"{}\"\n\
+                                                  upload-bucket-region = \"us-east-1\"",
+                                                 s3.url));
+    let mut cx = Context::new(Task::Release,
+                              env.work(),
+                              "nightly".to_string(),
+                              secrets.parse().unwrap(),
+                              Some(REV.to_string()),
+                              DATE.to_string()).unwrap();
+    assert_eq!(exit_code(cx.run()), 9);
+    assert!(s3.requests().iter().any(|r| r.starts_with("PUT ")), "{:?}", s3.requests());
+}
+
+#[test]
+fn resumes_after_every_phase() {
+    let phases = [Phase::Downloaded, Phase::Signed, Phase::SignaturesUploaded,
+                  Phase::ArchivePublished, Phase::DocsPublished, Phase::ReleasePublished,
+                  Phase::Indexed, Phase::Invalidated];
+    for &phase in phases.iter() {
+        let env = Env::new("resume");
+        env.live("nightly", "1.42.0-nightly (c9290dcee 2019-12-15)");
+        env.ci(REV, "nightly", VERSION);
+        let mut cx = env.context(Task::Release, "nightly", Some(REV));
+        cx.stop_after = Some(phase);
+        match cx.run() {
+            Err(Error::Network(ref msg)) if msg.starts_with("stopped") => {}
+            res => panic!("unexpected result stopping after {:?}: {:?}", phase, res),
+        }
+        let progress = state::load(&env.work(), "nightly", REV).unwrap();
+        assert_eq!((progress.phase, &progress.date[..]), (phase, DATE));
+
+        // The rest of the release happens the day after, but everything is
+        // still dated with the day it started.
+        let mut cx = env.context(Task::Release, "nightly", Some(REV));
+        cx.date = "2019-12-17".to_string();
+        match cx.run() {
+            Ok(Outcome::Released) => {}
+            res => panic!("unexpected result resuming after {:?}: {:?}", phase, res),
+        }
+        let dist = env.bucket().join("dist");
+        assert!(!dist.join("2019-12-17").exists(), "{:?}", phase);
+        let manifest = read_manifest(&dist.join("channel-rust-nightly.toml")).unwrap();
+        assert_eq!(rust_version(&manifest).unwrap().to_string(), VERSION);
+        assert_eq!(manifest["date"].as_str(), Some(DATE));
+        for (url, _) in manifest_artifacts(&manifest) {
+            let name = url_file_name(&url).unwrap();
+            assert!(dist.join(DATE).join(name).exists(), "{:?}: {}", phase, url);
+        }
+        let history = fs::read_to_string(dist.join("channel-history-nightly.json")).unwrap();
+        let history = serde_json::from_str::<serde_json::Value>(&history).unwrap();
+        let dates = history["releases"].as_array().unwrap().iter()
+            .map(|r| r["date"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(dates, [DATE], "{:?}", phase);
+        assert!(!state::path(&env.work(), "nightly").exists());
+    }
+}
@@ -0,0 +1,212 @@
+//! Parsing and comparison of `rustc` version strings such as
+//! `1.40.0 (73528e339 2019-12-16)` or `1.42.0-nightly (0d2817a43 2019-12-16)`,
+//! as found in `version` files and the `pkg.rust.version` of manifests.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use errors::{Error, Result};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub pre: Pre,
+    /// Abbreviated hash of the commit this was built from.
+    pub commit: Option<String>,
+    /// Date of that commit, as `YYYY-MM-DD`.
+    pub date: Option<String>,
+}
+
+/// The prerelease part of a version, ordered from least to most stable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pre {
+    Dev,
+    Nightly,
+    /// `-beta` or `-beta.N`
+    Beta(Option<u32>),
+    Stable,
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Result<Version> {
+        let bad = |why: &str| Error::Manifest(format!("invalid version `{}`: {}", s, why));
+        let mut parts = s.trim().splitn(2, ' ');
+        let number = parts.next().unwrap();
+        let (numbers, pre) = match number.find('-') {
+            Some(i) => (&number[..i], &number[i + 1..]),
+            None => (number, ""),
+        };
+        let pre = match pre {
+            "" => Pre::Stable,
+            "dev" => Pre::Dev,
+            "nightly" => Pre::Nightly,
+            "beta" => Pre::Beta(None),
+            pre => match pre.strip_prefix("beta.").and_then(|n| n.parse().ok()) {
+                Some(n) => Pre::Beta(Some(n)),
+                None => return Err(bad("unknown prerelease")),
+            },
+        };
+        let numbers = numbers.split('.')
+            .map(|n| n.parse::<u32>())
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .map_err(|_| bad("version numbers aren't numbers"))?;
+        if numbers.len() != 3 {
+            return Err(bad("expected three version numbers"))
+        }
+
+        // Then optionally `(hash date)`.
+        let (commit, date) = match parts.next() {
+            Some(rest) => {
+                let rest = rest.trim()
+                    .strip_prefix('(')
+                    .and_then(|r| r.strip_suffix(')'))
+                    .ok_or_else(|| bad("expected `(commit date)`"))?;
+                let mut info = rest.split_whitespace();
+                (info.next().map(|s| s.to_string()), info.next().map(|s| s.to_string()))
+            }
+            None => (None, None),
+        };
+
+        Ok(Version {
+            major: numbers[0],
+            minor: numbers[1],
+            patch: numbers[2],
+            pre,
+            commit,
+            date,
+        })
+    }
+
+    /// Just the version number, e.g. `1.40.0` or `1.41.0-beta.3`.
+    pub fn number(&self) -> String {
+        let pre = match self.pre {
+            Pre::Dev => "-dev".to_string(),
+            Pre::Nightly => "-nightly".to_string(),
+            Pre::Beta(None) => "-beta".to_string(),
+            Pre::Beta(Some(n)) => format!("-beta.{}", n),
+            Pre::Stable => String::new(),
+        };
+        format!("{}.{}.{}{}", self.major, self.minor, self.patch, pre)
+    }
+
+    /// Whether this was built from `rev`, a full commit hash.
+    pub fn built_from(&self, rev: &str) -> bool {
+        match self.commit {
+            Some(ref commit) => !commit.is_empty() && rev.starts_with(&commit[..]),
+            None => false,
+        }
+    }
+
+    /// Compares release order: version numbers first and then how stable the
+    /// release is. Builds of the same version are the same release whatever
+    /// their commit date, so a rebuild never looks like a downgrade.
+    pub fn cmp_release(&self, other: &Version) -> Ordering {
+        (self.major, self.minor, self.patch, self.pre)
+            .cmp(&(other.major, other.minor, other.patch, other.pre))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.number())?;
+        if let Some(ref commit) = self.commit {
+            write!(f, " ({}", commit)?;
+            if let Some(ref date) = self.date {
+                write!(f, " {}", date)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use errors::Error;
+    use super::{Pre, Version};
+
+    fn v(s: &str) -> Version {
+        Version::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parses_stable() {
+        let version = v("1.40.0 (73528e339 2019-12-16)");
+        assert_eq!((version.major, version.minor, version.patch), (1, 40, 0));
+        assert_eq!(version.pre, Pre::Stable);
+        assert_eq!(version.commit.as_deref(), Some("73528e339"));
+        assert_eq!(version.date.as_deref(), Some("2019-12-16"));
+        assert_eq!(version.number(), "1.40.0");
+        assert_eq!(version.to_string(), "1.40.0 (73528e339 2019-12-16)");
+        assert!(version.built_from("73528e339b3aa5c3d5d0df52da6cef5fd6efa5b6"));
+        assert!(!version.built_from("0d2817a43f72d0ba0e1ab11a89ba7a4c5e42b7b9"));
+    }
+
+    #[test]
+    fn parses_prereleases() {
+        assert_eq!(v("1.41.0-beta").pre, Pre::Beta(None));
+        assert_eq!(v("1.41.0-beta.3 (1234567ab 2019-12-01)").pre, Pre::Beta(Some(3)));
+        assert_eq!(v("1.42.0-nightly (0d2817a43 2019-12-16)").pre, Pre::Nightly);
+        assert_eq!(v("1.42.0-dev").pre, Pre::Dev);
+        for s in ["1.41.0-beta", "1.41.0-beta.3", "1.42.0-nightly", "1.42.0-dev"].iter() {
+            assert_eq!(v(s).number(), *s);
+            assert_eq!(v(s).commit, None);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let bad = ["", "1.40", "1.40.0.1", "1.x.0", "1.41.0-alpha", "1.41.0-beta.x",
+                   "1.40.0 73528e339 2019-12-16", "1.40.0 (73528e339"];
+        for s in bad.iter() {
+            match Version::parse(s) {
+                Err(Error::Manifest(msg)) => assert!(msg.contains("invalid version"), "{}", msg),
+                res => panic!("parsed `{}`: {:?}", s, res),
+            }
+        }
+    }
+
+    #[test]
+    fn orders_releases() {
+        // Versions in the same group are the same release.
+        let ordered: &[&[&str]] = &[
+            &["1.39.0 (4560ea788 2019-11-04)"],
+            &["1.40.0-dev"],
+            &["1.40.0-nightly (c9290dcee 2019-10-01)", "1.40.0-nightly (0d2817a43 2019-10-02)"],
+            &["1.40.0-beta"],
+            &["1.40.0-beta.1 (d7ed7fc4b 2019-10-03)"],
+            &["1.40.0-beta.10 (8a3ecaa3f 2019-11-20)"],
+            &["1.40.0 (73528e339 2019-12-16)"],
+            &["1.40.1 (ab7b0f5a8 2020-01-20)"],
+            &["1.41.0-beta.3 (1234567ab 2019-12-01)"],
+        ];
+        for (i, group) in ordered.iter().enumerate() {
+            for (j, other) in ordered.iter().enumerate() {
+                for a in group.iter() {
+                    for b in other.iter() {
+                        assert_eq!(v(a).cmp_release(&v(b)), i.cmp(&j), "{} vs {}", a, b);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn detects_downgrades() {
+        // What the guard before publishing stable and beta looks for.
+        let live = v("1.41.0-beta.3 (1234567ab 2019-12-01)");
+        assert_eq!(v("1.41.0-beta.2 (7654321ab 2019-11-25)").cmp_release(&live), Ordering::Less);
+        assert_eq!(v("1.40.0 (73528e339 2019-12-16)").cmp_release(&live), Ordering::Less);
+        assert_eq!(v("1.41.0-beta.3 (1234567ab 2019-12-01)").cmp_release(&live),
+                   Ordering::Equal);
+        assert_eq!(v("1.41.0-beta.4 (89abcdef0 2019-12-08)").cmp_release(&live),
+                   Ordering::Greater);
+        // A rebuild of the live version, even from an older commit, isn't.
+        assert_eq!(v("1.41.0-beta.3 (fedcba987 2019-11-30)").cmp_release(&live),
+                   Ordering::Equal);
+    }
+}